@@ -1,43 +1,138 @@
 -- API Key Management Database Schema
 -- SQLite implementation for secure API key system
+-- All timestamps are stored as RFC 3339 UTC strings with millisecond precision,
+-- e.g. "2024-05-01T12:34:56.789Z"
 
--- Users table
+-- Organizations table (tenants owning users and their keys)
+CREATE TABLE IF NOT EXISTS organizations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT UNIQUE NOT NULL,
+    created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+-- Organization for users created without one
+INSERT OR IGNORE INTO organizations (id, name) VALUES (1, 'default');
+
+-- Users table (people and service accounts; both can own API keys and hold roles)
 CREATE TABLE IF NOT EXISTS users (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
-    username TEXT UNIQUE NOT NULL,
-    email TEXT UNIQUE NOT NULL,
-    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    organization_id INTEGER NOT NULL DEFAULT 1,  -- tenant the user belongs to
+    account_type TEXT NOT NULL DEFAULT 'user',   -- "user" or "service"
+    team TEXT,                                   -- team owning a service account
+    username TEXT UNIQUE NOT NULL,               -- service account name for service accounts
+    email TEXT UNIQUE,                           -- NULL for service accounts
+    created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    updated_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    FOREIGN KEY (organization_id) REFERENCES organizations(id)
 );
 
 -- API Keys table
 CREATE TABLE IF NOT EXISTS api_keys (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER NOT NULL,
-    key_hash TEXT UNIQUE NOT NULL,  -- SHA256 hash of the actual API key
+    organization_id INTEGER NOT NULL DEFAULT 1,  -- tenant of the owning user
+    key_hash TEXT UNIQUE NOT NULL,  -- hash of the actual API key (see hash_algorithm)
+    hash_algorithm TEXT NOT NULL DEFAULT 'sha256',  -- scheme id, e.g. "sha256", "hmac-sha256", "hmac-sha256(sha256)"
+    needs_rehash BOOLEAN NOT NULL DEFAULT 0,        -- hash could not be migrated; upgraded on next use
     key_prefix TEXT NOT NULL,       -- "myapp"
     environment TEXT NOT NULL,      -- "prod", "dev", "test"
     version INTEGER NOT NULL DEFAULT 1,
     scopes TEXT NOT NULL,           -- JSON array of scopes
     is_active BOOLEAN NOT NULL DEFAULT 1,
-    issued_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    issued_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
     expires_at DATETIME,
     last_used_at DATETIME,
     usage_count INTEGER DEFAULT 0,
-    FOREIGN KEY (user_id) REFERENCES users(id)
+    revoked_at DATETIME,            -- set when the key is revoked
+    revoked_by TEXT,                -- who revoked the key
+    revocation_reason TEXT,
+    predecessor_id INTEGER,         -- key this one was rotated from
+    rotate_by DATETIME,             -- end of the grace period after rotation
+    max_token_ttl_seconds INTEGER,  -- cap on access token lifetime; NULL = server limits
+    key_last_four TEXT,             -- last 4 characters of the key, for display
+    name TEXT,                      -- "staging ingest"
+    description TEXT,
+    labels TEXT NOT NULL DEFAULT '{}',  -- JSON object of string labels
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (organization_id) REFERENCES organizations(id),
+    FOREIGN KEY (predecessor_id) REFERENCES api_keys(id)
+);
+
+-- Scopes table (registry of the scopes keys and tokens may be granted)
+CREATE TABLE IF NOT EXISTS scopes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT UNIQUE NOT NULL,        -- "read", "billing:read"
+    description TEXT NOT NULL,
+    created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+-- Built-in scopes, always registered
+INSERT OR IGNORE INTO scopes (name, description) VALUES
+    ('read', 'Read access to every resource'),
+    ('write', 'Write access to every resource; implies read'),
+    ('admin', 'Administrative access to every resource; implies write'),
+    ('platform:admin', 'Manage organizations and signing keys; only honoured in the default organization');
+
+-- Roles table (named bundles of scopes)
+CREATE TABLE IF NOT EXISTS roles (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER,          -- organization defining the role; NULL for built-in roles
+    name TEXT NOT NULL,               -- "viewer", "developer", "admin"; unique per organization
+    description TEXT NOT NULL,
+    scopes TEXT NOT NULL,             -- JSON array of scopes
+    created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    FOREIGN KEY (organization_id) REFERENCES organizations(id)
+);
+
+-- Built-in roles, always present and shared by every organization
+INSERT OR IGNORE INTO roles (name, description, scopes) VALUES
+    ('viewer', 'Read-only access', '["read"]'),
+    ('developer', 'Read and write access', '["read","write"]'),
+    ('admin', 'Unrestricted access', '["*"]');
+
+-- Role assignments; a key's scopes are limited to those its owner's roles grant
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id INTEGER NOT NULL,
+    role_id INTEGER NOT NULL,
+    assigned_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (user_id, role_id),
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (role_id) REFERENCES roles(id)
 );
 
 -- Access Tokens table (JWT tokens)
 CREATE TABLE IF NOT EXISTS access_tokens (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     api_key_id INTEGER NOT NULL,
+    jti TEXT,                         -- JWT ID claim of the token
+    family_id TEXT,                   -- refresh token family the token was issued in
     token_hash TEXT UNIQUE NOT NULL,  -- Hash of the JWT token
-    issued_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    hash_algorithm TEXT NOT NULL DEFAULT 'sha256',
+    needs_rehash BOOLEAN NOT NULL DEFAULT 0,
+    issued_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
     expires_at DATETIME NOT NULL,
     is_revoked BOOLEAN DEFAULT 0,
     FOREIGN KEY (api_key_id) REFERENCES api_keys(id)
 );
 
+-- Refresh Tokens table (single-use, rotated on every exchange)
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    api_key_id INTEGER NOT NULL,
+    family_id TEXT NOT NULL,          -- shared by every token descended from one API key exchange
+    token_hash TEXT UNIQUE NOT NULL,  -- hash of the refresh token
+    hash_algorithm TEXT NOT NULL DEFAULT 'sha256',
+    needs_rehash BOOLEAN NOT NULL DEFAULT 0,
+    scopes TEXT NOT NULL,             -- JSON array of scopes granted to the family
+    audience TEXT,                    -- aud claim of the access tokens issued from the family
+    access_token_ttl_seconds INTEGER, -- lifetime requested at exchange, reused on refresh
+    issued_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    expires_at DATETIME NOT NULL,
+    used_at DATETIME,                 -- set on exchange; a second use means the token was replayed
+    is_revoked BOOLEAN NOT NULL DEFAULT 0,
+    FOREIGN KEY (api_key_id) REFERENCES api_keys(id)
+);
+
 -- Usage Logs table
 CREATE TABLE IF NOT EXISTS usage_logs (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -47,7 +142,7 @@ CREATE TABLE IF NOT EXISTS usage_logs (
     ip_address TEXT,
     user_agent TEXT,
     success BOOLEAN NOT NULL,
-    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
     FOREIGN KEY (api_key_id) REFERENCES api_keys(id),
     FOREIGN KEY (access_token_id) REFERENCES access_tokens(id)
 );
@@ -57,5 +152,8 @@ CREATE INDEX IF NOT EXISTS idx_api_keys_user_id ON api_keys(user_id);
 CREATE INDEX IF NOT EXISTS idx_api_keys_key_hash ON api_keys(key_hash);
 CREATE INDEX IF NOT EXISTS idx_api_keys_is_active ON api_keys(is_active);
 CREATE INDEX IF NOT EXISTS idx_access_tokens_expires_at ON access_tokens(expires_at);
+CREATE INDEX IF NOT EXISTS idx_access_tokens_api_key_id ON access_tokens(api_key_id);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family_id ON refresh_tokens(family_id);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_api_key_id ON refresh_tokens(api_key_id);
 CREATE INDEX IF NOT EXISTS idx_usage_logs_api_key_id ON usage_logs(api_key_id);
 CREATE INDEX IF NOT EXISTS idx_usage_logs_created_at ON usage_logs(created_at); 
\ No newline at end of file
@@ -0,0 +1,386 @@
+use crate::database::Database;
+use crate::errors::ApiError;
+use crate::models::{Principal, Scope, ScopeSet};
+use crate::rate_limit::RateLimitManager;
+use crate::security::{ApiKeyService, TokenService};
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+// Header carrying a raw API key when no Bearer token is sent
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// Caller identity established from a Bearer access token or an API key header
+#[derive(Debug, Clone)]
+pub struct AuthenticatedKey {
+    // Id of the owning user or service account
+    pub user_id: i64,
+    pub principal: Principal,
+    pub api_key_id: i64,
+    pub organization_id: i64,
+    // Environment of the key (`prod`, `test`, ...)
+    pub environment: String,
+    pub scopes: ScopeSet,
+    pub method: AuthMethod,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    AccessToken { jti: String },
+    ApiKey,
+}
+
+impl AuthenticatedKey {
+    // Whether the granted scopes imply `scope` (`billing:*` grants `billing:read`)
+    pub fn has_scope(&self, scope: &str) -> bool {
+        Scope::parse(scope).is_ok_and(|scope| self.scopes.grants(&scope))
+    }
+
+    // Fail with 403 unless every required scope was granted
+    pub fn require_scopes(&self, required: &ScopeSet) -> Result<(), AuthError> {
+        let missing = self.scopes.missing(required);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(AuthError::insufficient_scope(
+                required.to_strings(),
+                missing.to_strings(),
+            ))
+        }
+    }
+}
+
+// 401/403 rejection with a JSON body and a RFC 6750 WWW-Authenticate header
+#[derive(Debug)]
+pub struct AuthError {
+    pub status: StatusCode,
+    pub error: &'static str,
+    pub message: String,
+    pub required_scopes: Vec<String>,
+    pub missing_scopes: Vec<String>,
+}
+
+impl AuthError {
+    pub fn unauthorized(message: String) -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            error: "invalid_token",
+            message,
+            required_scopes: Vec::new(),
+            missing_scopes: Vec::new(),
+        }
+    }
+
+    pub fn insufficient_scope(required_scopes: Vec<String>, missing_scopes: Vec<String>) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            error: "insufficient_scope",
+            message: format!("Missing required scopes: {}", missing_scopes.join(", ")),
+            required_scopes,
+            missing_scopes,
+        }
+    }
+}
+
+impl From<ApiError> for AuthError {
+    fn from(err: ApiError) -> Self {
+        match err.status_code() {
+            // Lookups that fail for an unknown key are still authentication failures
+            status if status.is_client_error() => AuthError::unauthorized(err.to_string()),
+            status => Self {
+                status,
+                error: "server_error",
+                message: err.to_string(),
+                required_scopes: Vec::new(),
+                missing_scopes: Vec::new(),
+            },
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let challenge = match self.status {
+            StatusCode::UNAUTHORIZED => Some(format!(r#"Bearer error="{}""#, self.error)),
+            StatusCode::FORBIDDEN => Some(format!(
+                r#"Bearer error="{}", scope="{}""#,
+                self.error,
+                self.required_scopes.join(" ")
+            )),
+            _ => None,
+        };
+
+        let body = Json(json!({
+            "success": false,
+            "error": self.error,
+            "message": self.message,
+            "required_scopes": self.required_scopes,
+            "missing_scopes": self.missing_scopes,
+        }));
+
+        let mut response = (self.status, body).into_response();
+        if let Some(value) = challenge.and_then(|c| HeaderValue::from_str(&c).ok()) {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, value);
+        }
+        response
+    }
+}
+
+// Client address (first `X-Forwarded-For` hop) and user agent recorded in usage logs
+pub fn client_details(headers: &HeaderMap) -> (Option<&str>, Option<&str>) {
+    let ip_address = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim);
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    (ip_address, user_agent)
+}
+
+// Validates credentials on incoming requests
+#[derive(Clone)]
+pub struct Authenticator {
+    pub api_key_service: ApiKeyService,
+    pub token_service: TokenService,
+    pub audience: Option<String>,
+    pub environments: Option<Vec<String>>,
+}
+
+impl Authenticator {
+    // Starts out bound to the serving environments of `api_key_service`, if any
+    pub fn new(api_key_service: ApiKeyService, token_service: TokenService) -> Self {
+        Self {
+            environments: api_key_service.serving_environments().map(<[String]>::to_vec),
+            api_key_service,
+            token_service,
+            audience: None,
+        }
+    }
+
+    // Only accept access tokens issued for this audience
+    pub fn with_audience(mut self, audience: String) -> Self {
+        self.audience = Some(audience);
+        self
+    }
+
+    // Only accept keys, and tokens issued from keys, of these environments, so that
+    // e.g. a `test` key is rejected on routes serving `prod`
+    pub fn with_environments(mut self, environments: &[&str]) -> Self {
+        self.environments = Some(environments.iter().map(|env| env.to_string()).collect());
+        self
+    }
+
+    fn check_environment(&self, environment: &str) -> Result<(), AuthError> {
+        match &self.environments {
+            Some(environments) if !environments.iter().any(|env| env == environment) => {
+                Err(ApiError::EnvironmentMismatch.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Authenticate from `Authorization: Bearer <access token>` or `X-API-Key: <api key>`.
+    // Every use of a known key on `endpoint` is recorded in the usage log.
+    pub fn authenticate(
+        &self,
+        endpoint: &str,
+        headers: &HeaderMap,
+    ) -> Result<AuthenticatedKey, AuthError> {
+        if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+            let token = authorization
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or_else(|| {
+                    AuthError::unauthorized(
+                        "Authorization header must be a Bearer token".to_string(),
+                    )
+                })?;
+
+            let claims = match &self.audience {
+                Some(audience) => self
+                    .token_service
+                    .validate_access_token_for_audience(token, audience)?,
+                None => self.token_service.validate_access_token(token)?,
+            };
+            let principal = claims.principal()?;
+            // Tokens issued before the claims existed take the organization and
+            // environment of their key
+            let (organization_id, environment) =
+                match (claims.organization_id, claims.environment.clone()) {
+                    (Some(organization_id), Some(environment)) => (organization_id, environment),
+                    (organization_id, environment) => {
+                        let key = self.api_key_service.db().get_api_key(claims.api_key_id)?;
+                        (
+                            organization_id.unwrap_or(key.organization_id),
+                            environment.unwrap_or(key.environment),
+                        )
+                    }
+                };
+            let checked = self.check_environment(&environment);
+            self.log_usage(claims.api_key_id, endpoint, headers, checked.is_ok());
+            checked?;
+
+            return Ok(AuthenticatedKey {
+                user_id: principal.id(),
+                principal,
+                api_key_id: claims.api_key_id,
+                organization_id,
+                environment,
+                scopes: claims.scope_set()?,
+                method: AuthMethod::AccessToken { jti: claims.jti },
+            });
+        }
+
+        if let Some(api_key) = headers.get(API_KEY_HEADER) {
+            let api_key = api_key
+                .to_str()
+                .map_err(|_| AuthError::unauthorized(ApiError::InvalidKeyFormat.to_string()))?;
+            let key = self.api_key_service.validate_api_key(api_key)?;
+            let checked = self.check_environment(&key.environment);
+            self.log_usage(key.id, endpoint, headers, checked.is_ok());
+            checked?;
+            let _ = self.api_key_service.db().update_api_key_usage(key.id);
+
+            return Ok(AuthenticatedKey {
+                user_id: key.user_id,
+                principal: self.api_key_service.db().get_principal(key.user_id)?,
+                api_key_id: key.id,
+                organization_id: key.organization_id,
+                environment: key.environment,
+                scopes: ScopeSet::parse(&key.scopes)?,
+                method: AuthMethod::ApiKey,
+            });
+        }
+
+        Err(AuthError::unauthorized(
+            "Missing Bearer token or X-API-Key header".to_string(),
+        ))
+    }
+
+    // Failing to record usage never fails the request
+    fn log_usage(&self, api_key_id: i64, endpoint: &str, headers: &HeaderMap, success: bool) {
+        let (ip_address, user_agent) = client_details(headers);
+        let _ = self.api_key_service.db().log_usage(
+            api_key_id,
+            None,
+            endpoint,
+            ip_address,
+            user_agent,
+            success,
+        );
+    }
+
+    // Layer that rejects requests lacking any of the given scopes:
+    // `.route_layer(authenticator.require_scopes(&["billing:read"]))`
+    //
+    // Panics if a scope is malformed, since routes are declared at startup
+    pub fn require_scopes(&self, scopes: &[&str]) -> RequireScopesLayer {
+        let scopes =
+            ScopeSet::parse(scopes).unwrap_or_else(|e| panic!("Invalid route scope: {}", e));
+        RequireScopesLayer {
+            authenticator: self.clone(),
+            scopes: Arc::new(scopes),
+        }
+    }
+}
+
+impl FromRef<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>> for Authenticator {
+    fn from_ref(state: &Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>) -> Self {
+        Authenticator::new(state.1.clone(), state.2.clone())
+    }
+}
+
+// Uses the identity established by `require_scopes` when present, otherwise
+// authenticates the request itself
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthenticatedKey
+where
+    Authenticator: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(key) = parts.extensions.get::<AuthenticatedKey>() {
+            return Ok(key.clone());
+        }
+        Authenticator::from_ref(state).authenticate(parts.uri.path(), &parts.headers)
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireScopesLayer {
+    authenticator: Authenticator,
+    scopes: Arc<ScopeSet>,
+}
+
+impl<S> Layer<S> for RequireScopesLayer {
+    type Service = RequireScopes<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireScopes {
+            inner,
+            authenticator: self.authenticator.clone(),
+            scopes: self.scopes.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireScopes<S> {
+    inner: S,
+    authenticator: Authenticator,
+    scopes: Arc<ScopeSet>,
+}
+
+impl<S> Service<Request<Body>> for RequireScopes<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        let authenticated = self
+            .authenticator
+            .authenticate(request.uri().path(), request.headers())
+            .and_then(|key| key.require_scopes(&self.scopes).map(|_| key));
+
+        // Call the instance that was polled ready and leave a fresh clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            match authenticated {
+                Ok(key) => {
+                    request.extensions_mut().insert(key);
+                    inner.call(request).await
+                }
+                Err(err) => Ok(err.into_response()),
+            }
+        })
+    }
+}
@@ -0,0 +1,120 @@
+// Create an administrator and an API key for them, to bootstrap a deployment or
+// an organization. Creating users and keys over HTTP needs such a key first.
+//
+// Usage: create_admin_key [--organization <id>] <username> <email> [path to database]
+//
+// Reads API_KEY_PREFIX, API_KEY_ENVIRONMENT, API_KEY_FORMAT, CREDENTIAL_PEPPER and
+// CREDENTIAL_HASH_ALGORITHM like the server. Admins of the default organization
+// also administer the platform (organizations and signing keys).
+
+use secure_api_key::database::{Database, DEFAULT_ORGANIZATION_ID};
+use secure_api_key::hashing::CredentialHashing;
+use secure_api_key::models::ApiKeyDetails;
+use secure_api_key::security::{key_last_four, ApiKeyService};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: create_admin_key [--organization <id>] <username> <email> [database]";
+
+fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+
+    let mut organization_id = DEFAULT_ORGANIZATION_ID;
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--organization" => match args.next().and_then(|id| id.parse().ok()) {
+                Some(id) => organization_id = id,
+                None => {
+                    eprintln!("--organization needs an organization id\n{}", USAGE);
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {}\n{}", arg, USAGE);
+                return ExitCode::FAILURE;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let (username, email, path) = match positional.as_slice() {
+        [username, email] => (username, email, "db/api_keys.db"),
+        [username, email, path] => (username, email, path.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let hashing = match CredentialHashing::from_env() {
+        Ok(hashing) => hashing,
+        Err(e) => {
+            eprintln!("Invalid credential hashing configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let key_format = match std::env::var("API_KEY_FORMAT").map(|format| format.parse()) {
+        Ok(Ok(format)) => format,
+        Ok(Err(e)) => {
+            eprintln!("Invalid API_KEY_FORMAT: {}", e);
+            return ExitCode::FAILURE;
+        }
+        Err(_) => Default::default(),
+    };
+    let db = match Database::new(path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let api_key_service = ApiKeyService::new(
+        db.clone(),
+        std::env::var("API_KEY_PREFIX").unwrap_or_else(|_| "myapp".to_string()),
+        std::env::var("API_KEY_ENVIRONMENT").unwrap_or_else(|_| "dev".to_string()),
+        std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string()),
+    )
+    .with_key_format(key_format)
+    .with_hashing(hashing);
+
+    let created = db
+        .create_user_in_organization(organization_id, username, email)
+        .and_then(|user_id| db.assign_role(user_id, "admin").map(|_| user_id))
+        .and_then(|user_id| {
+            let (api_key, key_hash) = api_key_service.generate_api_key()?;
+            let key_id = db.create_api_key_with_hash_algorithm(
+                user_id,
+                &key_hash,
+                api_key_service.hash_algorithm(),
+                api_key_service.prefix(),
+                api_key_service.environment(),
+                api_key_service.version(),
+                &["*".to_string()],
+                None,
+            )?;
+            db.set_api_key_last_four(key_id, key_last_four(api_key.expose_secret()))?;
+            db.update_api_key_details(
+                key_id,
+                &ApiKeyDetails {
+                    name: Some(format!("{} admin key", username)),
+                    ..ApiKeyDetails::default()
+                },
+            )?;
+            Ok((user_id, key_id, api_key))
+        });
+    let (user_id, key_id, api_key) = match created {
+        Ok(created) => created,
+        Err(e) => {
+            eprintln!("Failed to create admin key: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "Created admin {} (user {}) in organization {} with API key {}",
+        username, user_id, organization_id, key_id
+    );
+    println!("Store this key now; it cannot be shown again:");
+    println!("{}", api_key.expose_secret());
+    ExitCode::SUCCESS
+}
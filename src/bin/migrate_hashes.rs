@@ -0,0 +1,75 @@
+// Upgrade stored key and token hashes to the configured algorithm
+//
+// Usage: migrate_hashes [--dry-run] [path to database]
+//
+// Reads CREDENTIAL_PEPPER and CREDENTIAL_HASH_ALGORITHM like the server. Unkeyed
+// legacy hashes are wrapped in the configured keyed algorithm; hashes that cannot
+// be upgraded without the credential are flagged and re-hashed on next use.
+
+use secure_api_key::database::Database;
+use secure_api_key::hashing::{migrate_credential_hashes, CredentialHashing};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+
+    let mut dry_run = false;
+    let mut path = "db/api_keys.db".to_string();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            _ if arg.starts_with('-') => {
+                eprintln!(
+                    "Unknown option {}\nUsage: migrate_hashes [--dry-run] [database]",
+                    arg
+                );
+                return ExitCode::FAILURE;
+            }
+            _ => path = arg,
+        }
+    }
+
+    let hashing = match CredentialHashing::from_env() {
+        Ok(hashing) => hashing,
+        Err(e) => {
+            eprintln!("Invalid credential hashing configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let db = match Database::new(&path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let reports = match migrate_credential_hashes(&db, &hashing, dry_run) {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "{}Migrating credential hashes in {} to {}",
+        if dry_run { "[dry run] " } else { "" },
+        path,
+        hashing.algorithm()
+    );
+    for report in &reports {
+        println!(
+            "{}: {} current, {} upgraded, {} flagged for rehash",
+            report.table,
+            report.current,
+            report.upgraded,
+            report.flagged.len()
+        );
+        if !report.flagged.is_empty() {
+            let ids: Vec<String> = report.flagged.iter().map(|id| id.to_string()).collect();
+            println!("  flagged ids: {}", ids.join(", "));
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -1,53 +1,270 @@
 use crate::errors::ApiError;
-use crate::models::{AccessToken, ApiKey, User};
-use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
+use crate::hashing::HashAlgorithm;
+use crate::models::{
+    is_label_key, AccessToken, ApiKey, ApiKeyDetails, ApiKeyFilter, ApiKeyPage, ApiKeyStatus,
+    Organization, Principal, RefreshToken, RegisteredScope, Role, Scope, ScopeSet, ServiceAccount,
+    UsageLog, User,
+};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
+// Schema version recorded in PRAGMA user_version once data migrations have run
+const SCHEMA_VERSION: i32 = 2;
+
+// Role given to newly created users
+pub const DEFAULT_ROLE: &str = "developer";
+
+// Page size of key listings when none is requested, and the largest allowed
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+// Organization of users created without one, and of data that predates organizations
+pub const DEFAULT_ORGANIZATION_ID: i64 = 1;
+
+// Timestamp columns, all stored as canonical RFC 3339 UTC strings (see `to_db_timestamp`)
+const TIMESTAMP_COLUMNS: &[(&str, &str)] = &[
+    ("organizations", "created_at"),
+    ("users", "created_at"),
+    ("users", "updated_at"),
+    ("api_keys", "issued_at"),
+    ("api_keys", "expires_at"),
+    ("api_keys", "last_used_at"),
+    ("api_keys", "revoked_at"),
+    ("api_keys", "rotate_by"),
+    ("scopes", "created_at"),
+    ("roles", "created_at"),
+    ("user_roles", "assigned_at"),
+    ("access_tokens", "issued_at"),
+    ("access_tokens", "expires_at"),
+    ("refresh_tokens", "issued_at"),
+    ("refresh_tokens", "expires_at"),
+    ("refresh_tokens", "used_at"),
+    ("usage_logs", "created_at"),
+];
+
+const API_KEY_COLUMNS: &str = "id, user_id, key_hash, key_prefix, environment, version, scopes, is_active, issued_at, expires_at, last_used_at, usage_count, revoked_at, revoked_by, revocation_reason, predecessor_id, rotate_by, max_token_ttl_seconds, organization_id, key_last_four, name, description, labels, hash_algorithm";
+
 #[derive(Clone)]
 pub struct Database {
     conn: Arc<Mutex<Connection>>,
+    // Bumped on every revocation written through this handle, so in-process
+    // caches can notice revocations without polling the database
+    revocation_epoch: Arc<AtomicU64>,
 }
 
 impl Database {
     pub fn new(path: &str) -> Result<Self, ApiError> {
         let conn = Connection::open(path)?;
         conn.execute_batch(include_str!("../db/schema.sql"))?;
+        migrate(&conn)?;
         Ok(Database {
             conn: Arc::new(Mutex::new(conn)),
+            revocation_epoch: Arc::new(AtomicU64::new(0)),
         })
     }
 
-    // User operations
-    pub fn create_user(&self, username: &str, email: &str) -> Result<i64, ApiError> {
+    pub fn revocation_epoch(&self) -> u64 {
+        self.revocation_epoch.load(Ordering::Acquire)
+    }
+
+    fn bump_revocation_epoch(&self) {
+        self.revocation_epoch.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Organization operations
+    pub fn create_organization(&self, name: &str) -> Result<i64, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO organizations (name, created_at) VALUES (?, ?)",
+            params![name, to_db_timestamp(&Utc::now())],
+        )?;
+        if inserted == 0 {
+            return Err(ApiError::InvalidRequest(format!(
+                "Organization already exists: {}",
+                name
+            )));
+        }
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_organization(&self, organization_id: i64) -> Result<Organization, ApiError> {
         let conn = self.conn.lock().unwrap();
-        let user_id = conn.execute(
-            "INSERT INTO users (username, email) VALUES (?, ?)",
-            params![username, email],
+        conn.query_row(
+            "SELECT id, name, created_at FROM organizations WHERE id = ?",
+            params![organization_id],
+            map_organization_row,
+        )
+        .optional()?
+        .ok_or(ApiError::OrganizationNotFound)
+    }
+
+    pub fn list_organizations(&self) -> Result<Vec<Organization>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id, name, created_at FROM organizations ORDER BY id")?;
+        let organizations = stmt
+            .query_map([], map_organization_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(organizations)
+    }
+
+    // User operations. New users get the DEFAULT_ROLE.
+    pub fn create_user(&self, username: &str, email: &str) -> Result<i64, ApiError> {
+        self.create_user_in_organization(DEFAULT_ORGANIZATION_ID, username, email)
+    }
+
+    pub fn create_user_in_organization(
+        &self,
+        organization_id: i64,
+        username: &str,
+        email: &str,
+    ) -> Result<i64, ApiError> {
+        self.get_organization(organization_id)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = to_db_timestamp(&Utc::now());
+        tx.execute(
+            "INSERT INTO users (organization_id, username, email, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+            params![organization_id, username, email, now, now],
         )?;
-        Ok(user_id as i64)
+        let user_id = tx.last_insert_rowid();
+        tx.execute(
+            "INSERT INTO user_roles (user_id, role_id, assigned_at) SELECT ?, id, ? FROM roles WHERE name = ? AND organization_id IS NULL",
+            params![user_id, now, DEFAULT_ROLE],
+        )?;
+        tx.commit()?;
+        Ok(user_id)
     }
 
     pub fn get_user(&self, user_id: i64) -> Result<User, ApiError> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, username, email, created_at, updated_at FROM users WHERE id = ?",
+            "SELECT id, username, email, created_at, updated_at, organization_id FROM users WHERE id = ? AND account_type = 'user'",
         )?;
 
-        let user = stmt.query_row(params![user_id], |row| {
+        stmt.query_row(params![user_id], |row| {
             Ok(User {
                 id: row.get(0)?,
+                organization_id: row.get(5)?,
                 username: row.get(1)?,
                 email: row.get(2)?,
-                created_at: Utc::now(), // Always use current time
-                updated_at: Utc::now(), // Always use current time
+                created_at: parse_db_timestamp(3, row.get(3)?)?,
+                updated_at: parse_db_timestamp(4, row.get(4)?)?,
             })
-        })?;
+        })
+        .optional()?
+        .ok_or(ApiError::UserNotFound)
+    }
+
+    // Service account operations. Service accounts get the DEFAULT_ROLE like users.
+    pub fn create_service_account(
+        &self,
+        organization_id: i64,
+        name: &str,
+        team: Option<&str>,
+    ) -> Result<i64, ApiError> {
+        self.get_organization(organization_id)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = to_db_timestamp(&Utc::now());
+        tx.execute(
+            "INSERT INTO users (organization_id, account_type, team, username, created_at, updated_at) VALUES (?, 'service', ?, ?, ?, ?)",
+            params![organization_id, team, name, now, now],
+        )?;
+        let service_account_id = tx.last_insert_rowid();
+        tx.execute(
+            "INSERT INTO user_roles (user_id, role_id, assigned_at) SELECT ?, id, ? FROM roles WHERE name = ? AND organization_id IS NULL",
+            params![service_account_id, now, DEFAULT_ROLE],
+        )?;
+        tx.commit()?;
+        Ok(service_account_id)
+    }
 
-        Ok(user)
+    pub fn get_service_account(&self, service_account_id: i64) -> Result<ServiceAccount, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT id, organization_id, username, team, created_at, updated_at FROM users WHERE id = ? AND account_type = 'service'",
+            params![service_account_id],
+            map_service_account_row,
+        )
+        .optional()?
+        .ok_or(ApiError::ServiceAccountNotFound)
     }
 
-    // API Key operations
+    pub fn list_service_accounts(
+        &self,
+        organization_id: i64,
+    ) -> Result<Vec<ServiceAccount>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, organization_id, username, team, created_at, updated_at FROM users WHERE organization_id = ? AND account_type = 'service' ORDER BY id",
+        )?;
+        let accounts = stmt
+            .query_map(params![organization_id], map_service_account_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(accounts)
+    }
+
+    // Hand a service account, and with it every key it owns, to another team
+    pub fn transfer_service_account(
+        &self,
+        service_account_id: i64,
+        team: &str,
+    ) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE users SET team = ?, updated_at = ? WHERE id = ? AND account_type = 'service'",
+            params![team, to_db_timestamp(&Utc::now()), service_account_id],
+        )?;
+        if updated == 0 {
+            return Err(ApiError::ServiceAccountNotFound);
+        }
+        Ok(())
+    }
+
+    // Resolve the kind of principal behind a user id
+    pub fn get_principal(&self, user_id: i64) -> Result<Principal, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let account_type: String = conn
+            .query_row(
+                "SELECT account_type FROM users WHERE id = ?",
+                params![user_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(ApiError::UserNotFound)?;
+        Ok(match account_type.as_str() {
+            "service" => Principal::Service(user_id),
+            _ => Principal::User(user_id),
+        })
+    }
+
+    // Resolve a principal only if it belongs to the organization; users and
+    // service accounts of other organizations are reported as not found
+    pub fn get_principal_in_organization(
+        &self,
+        organization_id: i64,
+        user_id: i64,
+    ) -> Result<Principal, ApiError> {
+        let principal = self.get_principal(user_id)?;
+        let conn = self.conn.lock().unwrap();
+        let member: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM users WHERE id = ? AND organization_id = ?",
+                params![user_id, organization_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        member.map(|_| principal).ok_or(match principal {
+            Principal::User(_) => ApiError::UserNotFound,
+            Principal::Service(_) => ApiError::ServiceAccountNotFound,
+        })
+    }
+
+    // API Key operations. Keys belong to the organization of their owner.
+    #[allow(clippy::too_many_arguments)]
     pub fn create_api_key(
         &self,
         user_id: i64,
@@ -57,91 +274,798 @@ impl Database {
         version: i32,
         scopes: &[String],
         expires_at: Option<DateTime<Utc>>,
+    ) -> Result<i64, ApiError> {
+        self.create_api_key_with_hash_algorithm(
+            user_id,
+            key_hash,
+            HashAlgorithm::Sha256.id(),
+            key_prefix,
+            environment,
+            version,
+            scopes,
+            expires_at,
+        )
+    }
+
+    // Create a key whose hash was computed with `hash_algorithm`
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_api_key_with_hash_algorithm(
+        &self,
+        user_id: i64,
+        key_hash: &str,
+        hash_algorithm: &str,
+        key_prefix: &str,
+        environment: &str,
+        version: i32,
+        scopes: &[String],
+        expires_at: Option<DateTime<Utc>>,
     ) -> Result<i64, ApiError> {
         let conn = self.conn.lock().unwrap();
         let scopes_json = serde_json::to_string(scopes)?;
-        let expires_at_str = expires_at.map(|dt| dt.to_rfc3339());
+        let expires_at_str = expires_at.as_ref().map(to_db_timestamp);
 
-        let key_id = conn.execute(
-            "INSERT INTO api_keys (user_id, key_hash, key_prefix, environment, version, scopes, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
-            params![user_id, key_hash, key_prefix, environment, version, scopes_json, expires_at_str],
+        let inserted = conn.execute(
+            "INSERT INTO api_keys (user_id, organization_id, key_hash, hash_algorithm, key_prefix, environment, version, scopes, issued_at, expires_at) SELECT id, organization_id, ?, ?, ?, ?, ?, ?, ?, ? FROM users WHERE id = ?",
+            params![key_hash, hash_algorithm, key_prefix, environment, version, scopes_json, to_db_timestamp(&Utc::now()), expires_at_str, user_id],
         )?;
+        if inserted == 0 {
+            return Err(ApiError::UserNotFound);
+        }
 
-        Ok(key_id as i64)
+        Ok(conn.last_insert_rowid())
     }
 
     pub fn get_api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, ApiError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, user_id, key_hash, key_prefix, environment, version, scopes, is_active, issued_at, expires_at, last_used_at, usage_count FROM api_keys WHERE key_hash = ?"
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys WHERE key_hash = ?",
+            API_KEY_COLUMNS
+        ))?;
+
+        stmt.query_row(params![key_hash], map_api_key_row)
+            .optional()?
+            .ok_or(ApiError::KeyNotFound)
+    }
+
+    pub fn get_api_key(&self, key_id: i64) -> Result<ApiKey, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys WHERE id = ?",
+            API_KEY_COLUMNS
+        ))?;
+
+        stmt.query_row(params![key_id], map_api_key_row)
+            .optional()?
+            .ok_or(ApiError::KeyNotFound)
+    }
+
+    // Look up a key only if it belongs to the organization; keys of other
+    // organizations are reported as not found
+    pub fn get_api_key_for_organization(
+        &self,
+        organization_id: i64,
+        key_id: i64,
+    ) -> Result<ApiKey, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys WHERE id = ? AND organization_id = ?",
+            API_KEY_COLUMNS
+        ))?;
+
+        stmt.query_row(params![key_id, organization_id], map_api_key_row)
+            .optional()?
+            .ok_or(ApiError::KeyNotFound)
+    }
+
+    pub fn list_api_keys_for_organization(
+        &self,
+        organization_id: i64,
+    ) -> Result<Vec<ApiKey>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys WHERE organization_id = ? ORDER BY id",
+            API_KEY_COLUMNS
+        ))?;
+        let keys = stmt
+            .query_map(params![organization_id], map_api_key_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(keys)
+    }
+
+    // List keys matching the filter, one page at a time
+    pub fn list_api_keys(&self, filter: &ApiKeyFilter) -> Result<ApiKeyPage, ApiError> {
+        let limit = filter
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        let mut bind = |value: Value| {
+            values.push(value);
+            format!("?{}", values.len())
+        };
+
+        if let Some(organization_id) = filter.organization_id {
+            conditions.push(format!(
+                "organization_id = {}",
+                bind(organization_id.into())
+            ));
+        }
+        if let Some(user_id) = filter.user_id {
+            conditions.push(format!("user_id = {}", bind(user_id.into())));
+        }
+        if let Some(environment) = &filter.environment {
+            conditions.push(format!(
+                "environment = {}",
+                bind(environment.clone().into())
+            ));
+        }
+        if let Some(search) = &filter.search {
+            let pattern = format!("%{}%", escape_like(search));
+            let pattern = bind(pattern.into());
+            conditions.push(format!(
+                "(name LIKE {0} ESCAPE '\\' OR description LIKE {0} ESCAPE '\\')",
+                pattern
+            ));
+        }
+        if let Some((key, value)) = &filter.label {
+            // Label keys are restricted to characters that need no quoting in a JSON path
+            if !is_label_key(key) {
+                return Err(ApiError::InvalidRequest(format!(
+                    "Invalid label key: {:?}",
+                    key
+                )));
+            }
+            let path = bind(format!("$.\"{}\"", key).into());
+            let value = bind(value.clone().into());
+            conditions.push(format!("json_extract(labels, {}) = {}", path, value));
+        }
+        if let Some(status) = filter.status {
+            let now = bind(to_db_timestamp(&Utc::now()).into());
+            conditions.push(status_condition(status, &now));
+        }
+        if let Some(cursor) = filter.cursor {
+            conditions.push(format!("id > {}", bind(cursor.into())));
+        }
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        // Fetch one extra row to learn whether another page follows
+        let fetch = bind((limit as i64 + 1).into());
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys {} ORDER BY id LIMIT {}",
+            API_KEY_COLUMNS, where_clause, fetch
+        ))?;
+        let mut api_keys = stmt
+            .query_map(params_from_iter(values), map_api_key_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let next_cursor = if api_keys.len() > limit {
+            api_keys.truncate(limit);
+            api_keys.last().map(|api_key| api_key.id)
+        } else {
+            None
+        };
+        Ok(ApiKeyPage {
+            api_keys,
+            next_cursor,
+        })
+    }
+
+    // Set the name, description and labels of a key; fields that are None are kept
+    pub fn update_api_key_details(
+        &self,
+        key_id: i64,
+        details: &ApiKeyDetails,
+    ) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let labels = details.labels.as_ref().map(serde_json::to_string).transpose()?;
+        // Some(None) clears the column
+        let name = details.name.as_deref().map(non_empty);
+        let description = details.description.as_deref().map(non_empty);
+
+        let updated = conn.execute(
+            "UPDATE api_keys SET
+                 name = CASE WHEN ?1 THEN ?2 ELSE name END,
+                 description = CASE WHEN ?3 THEN ?4 ELSE description END,
+                 labels = COALESCE(?5, labels)
+             WHERE id = ?6",
+            params![
+                name.is_some(),
+                name.flatten(),
+                description.is_some(),
+                description.flatten(),
+                labels,
+                key_id
+            ],
         )?;
+        if updated == 0 {
+            return Err(ApiError::KeyNotFound);
+        }
+        Ok(())
+    }
 
-        let api_key = stmt.query_row(params![key_hash], |row| {
-            let scopes_json: String = row.get(6)?;
-            let scopes: Vec<String> = serde_json::from_str(&scopes_json).unwrap_or_default();
+    // Replace the stored hash of a key, e.g. after re-hashing it with a stronger algorithm
+    pub fn update_api_key_hash(
+        &self,
+        key_id: i64,
+        key_hash: &str,
+        hash_algorithm: &str,
+    ) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE api_keys SET key_hash = ?, hash_algorithm = ?, needs_rehash = 0 WHERE id = ?",
+            params![key_hash, hash_algorithm, key_id],
+        )?;
+        Ok(())
+    }
 
-            Ok(ApiKey {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                key_hash: row.get(2)?,
-                key_prefix: row.get(3)?,
-                environment: row.get(4)?,
-                version: row.get(5)?,
-                scopes,
-                is_active: row.get(7)?,
-                issued_at: Utc::now(), // Always use current time
-                expires_at: None,      // Always None for now
-                last_used_at: None,    // Always None for now
-                usage_count: row.get(11)?,
-            })
-        })?;
+    // Id, hash and hash algorithm of every credential in `table` (one of
+    // `hashing::CREDENTIAL_TABLES`), for migrating hashes in bulk
+    pub fn list_credential_hashes(
+        &self,
+        table: &str,
+    ) -> Result<Vec<(i64, String, String)>, ApiError> {
+        let hash_column = credential_hash_column(table)?;
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, {}, hash_algorithm FROM {} ORDER BY id",
+            hash_column, table
+        ))?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
 
-        Ok(api_key)
+    // Replace the stored hash of a credential in `table` and clear its rehash flag
+    pub fn update_credential_hash(
+        &self,
+        table: &str,
+        id: i64,
+        hash: &str,
+        hash_algorithm: &str,
+    ) -> Result<(), ApiError> {
+        let hash_column = credential_hash_column(table)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "UPDATE {} SET {} = ?, hash_algorithm = ?, needs_rehash = 0 WHERE id = ?",
+                table, hash_column
+            ),
+            params![hash, hash_algorithm, id],
+        )?;
+        Ok(())
+    }
+
+    // Mark a credential whose hash can only be upgraded once it is presented again
+    pub fn flag_credential_rehash(&self, table: &str, id: i64) -> Result<(), ApiError> {
+        credential_hash_column(table)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("UPDATE {} SET needs_rehash = 1 WHERE id = ?", table),
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    // Ids of the credentials in `table` flagged by `flag_credential_rehash`
+    pub fn list_flagged_credentials(&self, table: &str) -> Result<Vec<i64>, ApiError> {
+        credential_hash_column(table)?;
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id FROM {} WHERE needs_rehash = 1 ORDER BY id",
+            table
+        ))?;
+        let ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(ids)
+    }
+
+    // Remember the last characters of a newly issued key for display
+    pub fn set_api_key_last_four(&self, key_id: i64, key_last_four: &str) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE api_keys SET key_last_four = ? WHERE id = ?",
+            params![key_last_four, key_id],
+        )?;
+        Ok(())
     }
 
     pub fn update_api_key_usage(&self, key_id: i64) -> Result<(), ApiError> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
             "UPDATE api_keys SET last_used_at = ?, usage_count = usage_count + 1 WHERE id = ?",
-            params![Utc::now().to_rfc3339(), key_id],
+            params![to_db_timestamp(&Utc::now()), key_id],
+        )?;
+        Ok(())
+    }
+
+    // Revoke an API key and every access token issued from it.
+    // Returns the number of access tokens that were revoked.
+    pub fn revoke_api_key(
+        &self,
+        key_id: i64,
+        reason: &str,
+        revoked_by: Option<&str>,
+    ) -> Result<usize, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let updated = tx.execute(
+            "UPDATE api_keys SET is_active = 0, revoked_at = ?, revoked_by = ?, revocation_reason = ? WHERE id = ? AND revoked_at IS NULL",
+            params![to_db_timestamp(&Utc::now()), revoked_by, reason, key_id],
+        )?;
+
+        if updated == 0 {
+            let exists: Option<i64> = tx
+                .query_row("SELECT id FROM api_keys WHERE id = ?", params![key_id], |row| row.get(0))
+                .optional()?;
+            return Err(match exists {
+                Some(_) => ApiError::KeyRevoked,
+                None => ApiError::KeyNotFound,
+            });
+        }
+
+        let revoked_tokens = tx.execute(
+            "UPDATE access_tokens SET is_revoked = 1 WHERE api_key_id = ? AND is_revoked = 0",
+            params![key_id],
+        )?;
+        tx.execute(
+            "UPDATE refresh_tokens SET is_revoked = 1 WHERE api_key_id = ? AND is_revoked = 0",
+            params![key_id],
+        )?;
+
+        tx.commit()?;
+        self.bump_revocation_epoch();
+        Ok(revoked_tokens)
+    }
+
+    // Issue a successor for an API key, keeping the predecessor valid until `rotate_by`.
+    // The successor inherits the owner, prefix, environment, scopes, name, description
+    // and labels of the predecessor.
+    #[allow(clippy::too_many_arguments)]
+    pub fn rotate_api_key(
+        &self,
+        predecessor_id: i64,
+        key_hash: &str,
+        hash_algorithm: &str,
+        version: i32,
+        expires_at: Option<DateTime<Utc>>,
+        rotate_by: DateTime<Utc>,
+    ) -> Result<i64, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let updated = tx.execute(
+            "UPDATE api_keys SET rotate_by = ? WHERE id = ? AND is_active = 1 AND revoked_at IS NULL AND rotate_by IS NULL",
+            params![to_db_timestamp(&rotate_by), predecessor_id],
+        )?;
+        if updated == 0 {
+            return Err(ApiError::InvalidRequest(
+                "API key cannot be rotated (inactive, revoked or already rotated)".to_string(),
+            ));
+        }
+
+        tx.execute(
+            "INSERT INTO api_keys (user_id, organization_id, key_hash, hash_algorithm, key_prefix, environment, version, scopes, issued_at, expires_at, predecessor_id, max_token_ttl_seconds, name, description, labels) SELECT user_id, organization_id, ?, ?, key_prefix, environment, ?, scopes, ?, ?, id, max_token_ttl_seconds, name, description, labels FROM api_keys WHERE id = ?",
+            params![
+                key_hash,
+                hash_algorithm,
+                version,
+                to_db_timestamp(&Utc::now()),
+                expires_at.as_ref().map(to_db_timestamp),
+                predecessor_id
+            ],
+        )?;
+        let key_id = tx.last_insert_rowid();
+
+        tx.commit()?;
+        Ok(key_id)
+    }
+
+    // Cap the lifetime of access tokens issued for a key (None = server limits only)
+    pub fn set_api_key_max_token_ttl(
+        &self,
+        key_id: i64,
+        max_token_ttl_seconds: Option<i64>,
+    ) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE api_keys SET max_token_ttl_seconds = ? WHERE id = ?",
+            params![max_token_ttl_seconds, key_id],
+        )?;
+        if updated == 0 {
+            return Err(ApiError::KeyNotFound);
+        }
+        Ok(())
+    }
+
+    pub fn deactivate_api_key(&self, key_id: i64) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE api_keys SET is_active = 0 WHERE id = ?",
+            params![key_id],
         )?;
         Ok(())
     }
 
+    // Scope registry operations
+    pub fn register_scope(&self, scope: &Scope, description: &str) -> Result<i64, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO scopes (name, description, created_at) VALUES (?, ?, ?)",
+            params![scope.to_string(), description, to_db_timestamp(&Utc::now())],
+        )?;
+        if inserted == 0 {
+            return Err(ApiError::InvalidRequest(format!(
+                "Scope already registered: {}",
+                scope
+            )));
+        }
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn list_scopes(&self) -> Result<Vec<RegisteredScope>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT id, name, description, created_at FROM scopes ORDER BY name")?;
+        let scopes = stmt
+            .query_map([], |row| {
+                Ok(RegisteredScope {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    description: row.get(2)?,
+                    created_at: parse_db_timestamp(3, row.get(3)?)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(scopes)
+    }
+
+    // Fail with InvalidRequest listing every scope that is not in the registry.
+    // A wildcard is known when it covers at least one registered scope.
+    pub fn check_scopes_registered(&self, scopes: &ScopeSet) -> Result<(), ApiError> {
+        let registered = self
+            .list_scopes()?
+            .iter()
+            .filter_map(|scope| Scope::parse(&scope.name).ok())
+            .collect::<Vec<_>>();
+
+        let unknown: Vec<String> = scopes
+            .iter()
+            .filter(|scope| {
+                if scope.is_wildcard() {
+                    !registered.iter().any(|known| scope.implies(known))
+                } else {
+                    !registered.contains(scope)
+                }
+            })
+            .map(Scope::to_string)
+            .collect();
+
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::InvalidRequest(format!(
+                "Unknown scopes: {}",
+                unknown.join(", ")
+            )))
+        }
+    }
+
+    // Role operations. Roles belong to an organization; the built-in roles
+    // (no organization) are shared by all of them.
+    pub fn create_role(
+        &self,
+        organization_id: i64,
+        name: &str,
+        description: &str,
+        scopes: &ScopeSet,
+    ) -> Result<i64, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        // Names must not shadow a built-in role or repeat one of the organization's
+        let existing: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM roles WHERE name = ? AND (organization_id IS NULL OR organization_id = ?)",
+                params![name, organization_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if existing.is_some() {
+            return Err(ApiError::InvalidRequest(format!(
+                "Role already exists: {}",
+                name
+            )));
+        }
+        conn.execute(
+            "INSERT INTO roles (organization_id, name, description, scopes, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![
+                organization_id,
+                name,
+                description,
+                serde_json::to_string(&scopes.to_strings())?,
+                to_db_timestamp(&Utc::now())
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    // Built-in roles and the roles of the organization
+    pub fn list_roles(&self, organization_id: i64) -> Result<Vec<Role>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, description, scopes, created_at, organization_id FROM roles WHERE organization_id IS NULL OR organization_id = ? ORDER BY name",
+        )?;
+        let roles = stmt
+            .query_map(params![organization_id], map_role_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(roles)
+    }
+
+    pub fn get_user_roles(&self, user_id: i64) -> Result<Vec<Role>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT r.id, r.name, r.description, r.scopes, r.created_at, r.organization_id FROM roles r JOIN user_roles ur ON ur.role_id = r.id WHERE ur.user_id = ? ORDER BY r.name",
+        )?;
+        let roles = stmt
+            .query_map(params![user_id], map_role_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(roles)
+    }
+
+    // Assign a built-in role or a role of the user's organization; roles of other
+    // organizations are reported as not found. Assigning a role the user already
+    // has is a no-op.
+    pub fn assign_role(&self, user_id: i64, role: &str) -> Result<(), ApiError> {
+        self.get_principal(user_id)?;
+        let conn = self.conn.lock().unwrap();
+        let role_id: i64 = conn
+            .query_row(
+                "SELECT r.id FROM roles r JOIN users u ON u.id = ? WHERE r.name = ? AND (r.organization_id IS NULL OR r.organization_id = u.organization_id)",
+                params![user_id, role],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(ApiError::RoleNotFound)?;
+        conn.execute(
+            "INSERT OR IGNORE INTO user_roles (user_id, role_id, assigned_at) VALUES (?, ?, ?)",
+            params![user_id, role_id, to_db_timestamp(&Utc::now())],
+        )?;
+        Ok(())
+    }
+
+    // Remove a role from a user; returns false if the user did not have it
+    // Removing a role revokes the access tokens of every key whose effective
+    // scopes it narrows, so tokens never outlive the scopes they carry.
+    // Their refresh tokens stay usable and are narrowed on refresh.
+    pub fn unassign_role(&self, user_id: i64, role: &str) -> Result<bool, ApiError> {
+        let granted_before = self.get_user_role_scopes(user_id)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let removed = tx.execute(
+            "DELETE FROM user_roles WHERE user_id = ? AND role_id IN (SELECT id FROM roles WHERE name = ?)",
+            params![user_id, role],
+        )?;
+        if removed == 0 {
+            return Ok(false);
+        }
+
+        let granted_after: Vec<String> = {
+            let mut stmt = tx.prepare(
+                "SELECT r.id, r.name, r.description, r.scopes, r.created_at, r.organization_id FROM roles r JOIN user_roles ur ON ur.role_id = r.id WHERE ur.user_id = ?",
+            )?;
+            let roles = stmt
+                .query_map(params![user_id], map_role_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            roles.into_iter().flat_map(|role| role.scopes).collect()
+        };
+        let granted_after = ScopeSet::parse(&granted_after)?;
+
+        let keys: Vec<(i64, String)> = {
+            let mut stmt = tx.prepare("SELECT id, scopes FROM api_keys WHERE user_id = ? AND revoked_at IS NULL")?;
+            let rows = stmt.query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        let mut revoked_tokens = 0;
+        for (key_id, scopes_json) in keys {
+            let key_scopes = ScopeSet::parse(&serde_json::from_str::<Vec<String>>(&scopes_json)?)?;
+            if key_scopes.intersect(&granted_before) != key_scopes.intersect(&granted_after) {
+                revoked_tokens += tx.execute(
+                    "UPDATE access_tokens SET is_revoked = 1 WHERE api_key_id = ? AND is_revoked = 0",
+                    params![key_id],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        drop(conn);
+        if revoked_tokens > 0 {
+            self.bump_revocation_epoch();
+        }
+        Ok(true)
+    }
+
+    // Union of the scopes granted by a user's roles
+    pub fn get_user_role_scopes(&self, user_id: i64) -> Result<ScopeSet, ApiError> {
+        let scopes: Vec<String> = self
+            .get_user_roles(user_id)?
+            .into_iter()
+            .flat_map(|role| role.scopes)
+            .collect();
+        ScopeSet::parse(&scopes)
+    }
+
+    // The key's scopes narrowed to what its owner's roles currently grant
+    pub fn get_effective_scopes(&self, api_key: &ApiKey) -> Result<ScopeSet, ApiError> {
+        let key_scopes = ScopeSet::parse(&api_key.scopes)?;
+        Ok(key_scopes.intersect(&self.get_user_role_scopes(api_key.user_id)?))
+    }
+
     // Access Token operations
     pub fn create_access_token(
         &self,
         api_key_id: i64,
+        jti: &str,
+        family_id: Option<&str>,
         token_hash: &str,
+        hash_algorithm: &str,
         expires_at: DateTime<Utc>,
     ) -> Result<i64, ApiError> {
         let conn = self.conn.lock().unwrap();
-        let token_id = conn.execute(
-            "INSERT INTO access_tokens (api_key_id, token_hash, expires_at) VALUES (?, ?, ?)",
-            params![api_key_id, token_hash, expires_at.to_rfc3339()],
+        conn.execute(
+            "INSERT INTO access_tokens (api_key_id, jti, family_id, token_hash, hash_algorithm, issued_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![api_key_id, jti, family_id, token_hash, hash_algorithm, to_db_timestamp(&Utc::now()), to_db_timestamp(&expires_at)],
         )?;
 
-        Ok(token_id as i64)
+        Ok(conn.last_insert_rowid())
     }
 
     pub fn get_access_token_by_hash(&self, token_hash: &str) -> Result<AccessToken, ApiError> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, api_key_id, token_hash, issued_at, expires_at, is_revoked FROM access_tokens WHERE token_hash = ?"
+            "SELECT id, api_key_id, jti, token_hash, issued_at, expires_at, is_revoked, hash_algorithm FROM access_tokens WHERE token_hash = ?"
+        )?;
+
+        let token = stmt
+            .query_row(params![token_hash], |row| {
+                Ok(AccessToken {
+                    id: row.get(0)?,
+                    api_key_id: row.get(1)?,
+                    jti: row.get(2)?,
+                    token_hash: row.get(3)?,
+                    issued_at: parse_db_timestamp(4, row.get(4)?)?,
+                    expires_at: parse_db_timestamp(5, row.get(5)?)?,
+                    is_revoked: row.get(6)?,
+                    hash_algorithm: row.get(7)?,
+                })
+            })
+            .optional()?
+            .ok_or(ApiError::InvalidToken)?;
+
+        Ok(token)
+    }
+
+    // Revoke a single access token by its JWT ID
+    pub fn revoke_access_token(&self, jti: &str) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE access_tokens SET is_revoked = 1 WHERE jti = ?",
+            params![jti],
+        )?;
+        if updated == 0 {
+            return Err(ApiError::InvalidToken);
+        }
+        drop(conn);
+
+        self.bump_revocation_epoch();
+        Ok(())
+    }
+
+    // JWT IDs of revoked access tokens that have not expired yet
+    pub fn get_revoked_token_ids(&self, now: DateTime<Utc>) -> Result<Vec<String>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT jti FROM access_tokens WHERE is_revoked = 1 AND jti IS NOT NULL AND expires_at > ?",
+        )?;
+        let ids = stmt
+            .query_map(params![to_db_timestamp(&now)], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(ids)
+    }
+
+    // Refresh Token operations
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_refresh_token(
+        &self,
+        api_key_id: i64,
+        family_id: &str,
+        token_hash: &str,
+        hash_algorithm: &str,
+        scopes: &[String],
+        audience: Option<&str>,
+        access_token_ttl_seconds: Option<i64>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<i64, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO refresh_tokens (api_key_id, family_id, token_hash, hash_algorithm, scopes, audience, access_token_ttl_seconds, issued_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                api_key_id,
+                family_id,
+                token_hash,
+                hash_algorithm,
+                serde_json::to_string(scopes)?,
+                audience,
+                access_token_ttl_seconds,
+                to_db_timestamp(&Utc::now()),
+                to_db_timestamp(&expires_at)
+            ],
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_refresh_token_by_hash(&self, token_hash: &str) -> Result<RefreshToken, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, api_key_id, family_id, token_hash, scopes, issued_at, expires_at, used_at, is_revoked, audience, access_token_ttl_seconds, hash_algorithm FROM refresh_tokens WHERE token_hash = ?"
         )?;
 
-        let token = stmt.query_row(params![token_hash], |row| {
-            Ok(AccessToken {
+        stmt.query_row(params![token_hash], |row| {
+            let scopes_json: String = row.get(4)?;
+            Ok(RefreshToken {
                 id: row.get(0)?,
                 api_key_id: row.get(1)?,
-                token_hash: row.get(2)?,
-                issued_at: Utc::now(), // Always use current time
-                expires_at: Utc::now() + chrono::Duration::hours(1), // 1 hour from now
-                is_revoked: row.get(5)?,
+                family_id: row.get(2)?,
+                token_hash: row.get(3)?,
+                scopes: serde_json::from_str(&scopes_json).unwrap_or_default(),
+                issued_at: parse_db_timestamp(5, row.get(5)?)?,
+                expires_at: parse_db_timestamp(6, row.get(6)?)?,
+                used_at: parse_optional_db_timestamp(7, row.get(7)?)?,
+                is_revoked: row.get(8)?,
+                audience: row.get(9)?,
+                access_token_ttl_seconds: row.get(10)?,
+                hash_algorithm: row.get(11)?,
             })
-        })?;
+        })
+        .optional()?
+        .ok_or(ApiError::InvalidToken)
+    }
 
-        Ok(token)
+    // Atomically consume a refresh token. Returns false if it had already been used.
+    pub fn mark_refresh_token_used(&self, token_id: i64) -> Result<bool, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE refresh_tokens SET used_at = ? WHERE id = ? AND used_at IS NULL",
+            params![to_db_timestamp(&Utc::now()), token_id],
+        )?;
+        Ok(updated == 1)
+    }
+
+    // Revoke every refresh and access token in a family.
+    // Returns the number of access tokens that were revoked.
+    pub fn revoke_token_family(&self, family_id: &str) -> Result<usize, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "UPDATE refresh_tokens SET is_revoked = 1 WHERE family_id = ? AND is_revoked = 0",
+            params![family_id],
+        )?;
+        let revoked_tokens = tx.execute(
+            "UPDATE access_tokens SET is_revoked = 1 WHERE family_id = ? AND is_revoked = 0",
+            params![family_id],
+        )?;
+
+        tx.commit()?;
+        self.bump_revocation_epoch();
+        Ok(revoked_tokens)
     }
 
     // Usage Log operations
@@ -156,9 +1080,362 @@ impl Database {
     ) -> Result<(), ApiError> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "INSERT INTO usage_logs (api_key_id, access_token_id, endpoint, ip_address, user_agent, success) VALUES (?, ?, ?, ?, ?, ?)",
-            params![api_key_id, access_token_id, endpoint, ip_address, user_agent, success],
+            "INSERT INTO usage_logs (api_key_id, access_token_id, endpoint, ip_address, user_agent, success, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![api_key_id, access_token_id, endpoint, ip_address, user_agent, success, to_db_timestamp(&Utc::now())],
         )?;
         Ok(())
     }
+
+    // Most recent usage of the organization's keys (optionally one key), newest first
+    pub fn list_usage_logs(
+        &self,
+        organization_id: i64,
+        api_key_id: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<UsageLog>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT l.id, l.api_key_id, k.name, l.access_token_id, l.endpoint, l.ip_address, l.user_agent, l.success, l.created_at
+             FROM usage_logs l JOIN api_keys k ON k.id = l.api_key_id
+             WHERE k.organization_id = ?1 AND (?2 IS NULL OR l.api_key_id = ?2)
+             ORDER BY l.id DESC LIMIT ?3",
+        )?;
+        let logs = stmt
+            .query_map(
+                params![organization_id, api_key_id, limit.clamp(1, MAX_PAGE_SIZE) as i64],
+                map_usage_log_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(logs)
+    }
+}
+
+fn map_usage_log_row(row: &Row) -> rusqlite::Result<UsageLog> {
+    Ok(UsageLog {
+        id: row.get(0)?,
+        api_key_id: row.get(1)?,
+        api_key_name: row.get(2)?,
+        access_token_id: row.get(3)?,
+        endpoint: row.get(4)?,
+        ip_address: row.get(5)?,
+        user_agent: row.get(6)?,
+        success: row.get(7)?,
+        created_at: parse_db_timestamp(8, row.get(8)?)?,
+    })
+}
+
+fn non_empty(text: &str) -> Option<&str> {
+    (!text.is_empty()).then_some(text)
+}
+
+// Escape LIKE wildcards so user input is matched literally (with `ESCAPE '\'`)
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn map_api_key_row(row: &Row) -> rusqlite::Result<ApiKey> {
+    let scopes_json: String = row.get(6)?;
+    let scopes: Vec<String> = serde_json::from_str(&scopes_json).unwrap_or_default();
+
+    Ok(ApiKey {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        key_hash: row.get(2)?,
+        key_prefix: row.get(3)?,
+        environment: row.get(4)?,
+        version: row.get(5)?,
+        scopes,
+        is_active: row.get(7)?,
+        issued_at: parse_db_timestamp(8, row.get(8)?)?,
+        expires_at: parse_optional_db_timestamp(9, row.get(9)?)?,
+        last_used_at: parse_optional_db_timestamp(10, row.get(10)?)?,
+        usage_count: row.get(11)?,
+        revoked_at: parse_optional_db_timestamp(12, row.get(12)?)?,
+        revoked_by: row.get(13)?,
+        revocation_reason: row.get(14)?,
+        predecessor_id: row.get(15)?,
+        rotate_by: parse_optional_db_timestamp(16, row.get(16)?)?,
+        max_token_ttl_seconds: row.get(17)?,
+        organization_id: row.get(18)?,
+        key_last_four: row.get(19)?,
+        name: row.get(20)?,
+        description: row.get(21)?,
+        labels: serde_json::from_str(&row.get::<_, String>(22)?).unwrap_or_default(),
+        hash_algorithm: row.get(23)?,
+    })
+}
+
+// SQL condition selecting keys in `status` at the time bound to `now`; mirrors
+// `ApiKey::status`
+fn status_condition(status: ApiKeyStatus, now: &str) -> String {
+    let in_use = format!(
+        "revoked_at IS NULL AND is_active = 1 AND (rotate_by IS NULL OR rotate_by > {})",
+        now
+    );
+    let unexpired = format!("(expires_at IS NULL OR expires_at > {})", now);
+    match status {
+        ApiKeyStatus::Revoked => "revoked_at IS NOT NULL".to_string(),
+        ApiKeyStatus::Inactive => format!(
+            "revoked_at IS NULL AND (is_active = 0 OR rotate_by <= {})",
+            now
+        ),
+        ApiKeyStatus::Expired => format!("{} AND expires_at <= {}", in_use, now),
+        ApiKeyStatus::Rotating => format!("{} AND rotate_by IS NOT NULL AND {}", in_use, unexpired),
+        ApiKeyStatus::Active => format!("{} AND rotate_by IS NULL AND {}", in_use, unexpired),
+    }
+}
+
+// Canonical on-disk representation of a timestamp: RFC 3339 in UTC with millisecond
+// precision (e.g. "2024-05-01T12:34:56.789Z"). The fixed width keeps string
+// comparisons in SQL consistent with chronological order.
+pub(crate) fn to_db_timestamp(dt: &DateTime<Utc>) -> String {
+    dt.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn map_organization_row(row: &Row) -> rusqlite::Result<Organization> {
+    Ok(Organization {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: parse_db_timestamp(2, row.get(2)?)?,
+    })
+}
+
+fn map_service_account_row(row: &Row) -> rusqlite::Result<ServiceAccount> {
+    Ok(ServiceAccount {
+        id: row.get(0)?,
+        organization_id: row.get(1)?,
+        name: row.get(2)?,
+        team: row.get(3)?,
+        created_at: parse_db_timestamp(4, row.get(4)?)?,
+        updated_at: parse_db_timestamp(5, row.get(5)?)?,
+    })
+}
+
+fn map_role_row(row: &Row) -> rusqlite::Result<Role> {
+    let scopes_json: String = row.get(3)?;
+    Ok(Role {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        scopes: serde_json::from_str(&scopes_json).unwrap_or_default(),
+        created_at: parse_db_timestamp(4, row.get(4)?)?,
+        organization_id: row.get(5)?,
+    })
+}
+
+fn parse_db_timestamp(idx: usize, value: String) -> rusqlite::Result<DateTime<Utc>> {
+    parse_timestamp(&value).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            Type::Text,
+            format!("invalid timestamp: {}", value).into(),
+        )
+    })
+}
+
+fn parse_optional_db_timestamp(
+    idx: usize,
+    value: Option<String>,
+) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    value.map(|value| parse_db_timestamp(idx, value)).transpose()
+}
+
+// Accepts the canonical format as well as the legacy SQLite CURRENT_TIMESTAMP
+// format ("YYYY-MM-DD HH:MM:SS", always UTC) written by older versions.
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+                .ok()
+                .map(|dt| dt.and_utc())
+        })
+}
+
+// Column holding the credential hash of a table with stored credentials. Table
+// names are checked against this list before being formatted into SQL.
+fn credential_hash_column(table: &str) -> Result<&'static str, ApiError> {
+    match table {
+        "api_keys" => Ok("key_hash"),
+        "access_tokens" | "refresh_tokens" => Ok("token_hash"),
+        _ => Err(ApiError::InvalidRequest(format!(
+            "{} does not store credentials",
+            table
+        ))),
+    }
+}
+
+// Bring databases created by older versions of schema.sql up to date
+fn migrate(conn: &Connection) -> Result<(), ApiError> {
+    add_column_if_missing(conn, "api_keys", "revoked_at", "DATETIME")?;
+    add_column_if_missing(conn, "api_keys", "revoked_by", "TEXT")?;
+    add_column_if_missing(conn, "api_keys", "revocation_reason", "TEXT")?;
+    add_column_if_missing(conn, "api_keys", "predecessor_id", "INTEGER REFERENCES api_keys(id)")?;
+    add_column_if_missing(conn, "api_keys", "rotate_by", "DATETIME")?;
+    add_column_if_missing(conn, "api_keys", "max_token_ttl_seconds", "INTEGER")?;
+    add_column_if_missing(conn, "api_keys", "key_last_four", "TEXT")?;
+    add_column_if_missing(conn, "api_keys", "name", "TEXT")?;
+    add_column_if_missing(conn, "api_keys", "description", "TEXT")?;
+    add_column_if_missing(conn, "api_keys", "labels", "TEXT NOT NULL DEFAULT '{}'")?;
+    add_column_if_missing(conn, "api_keys", "hash_algorithm", "TEXT NOT NULL DEFAULT 'sha256'")?;
+    add_column_if_missing(conn, "access_tokens", "hash_algorithm", "TEXT NOT NULL DEFAULT 'sha256'")?;
+    add_column_if_missing(conn, "refresh_tokens", "hash_algorithm", "TEXT NOT NULL DEFAULT 'sha256'")?;
+    add_column_if_missing(conn, "api_keys", "needs_rehash", "BOOLEAN NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "access_tokens", "needs_rehash", "BOOLEAN NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "refresh_tokens", "needs_rehash", "BOOLEAN NOT NULL DEFAULT 0")?;
+    // SQLite refuses to add a REFERENCES column with a non-NULL default
+    add_column_if_missing(conn, "api_keys", "organization_id", "INTEGER NOT NULL DEFAULT 1")?;
+    add_column_if_missing(conn, "users", "organization_id", "INTEGER NOT NULL DEFAULT 1")?;
+    if !column_exists(conn, "users", "account_type")? {
+        rebuild_users_for_service_accounts(conn)?;
+    }
+    if !column_exists(conn, "roles", "organization_id")? {
+        rebuild_roles_for_organizations(conn)?;
+    }
+    add_column_if_missing(conn, "access_tokens", "jti", "TEXT")?;
+    add_column_if_missing(conn, "access_tokens", "family_id", "TEXT")?;
+    add_column_if_missing(conn, "refresh_tokens", "audience", "TEXT")?;
+    add_column_if_missing(conn, "refresh_tokens", "access_token_ttl_seconds", "INTEGER")?;
+    conn.execute_batch(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_access_tokens_jti ON access_tokens(jti);
+         CREATE INDEX IF NOT EXISTS idx_access_tokens_family_id ON access_tokens(family_id);
+         CREATE INDEX IF NOT EXISTS idx_users_organization_id ON users(organization_id);
+         CREATE INDEX IF NOT EXISTS idx_api_keys_organization_id ON api_keys(organization_id);
+         CREATE UNIQUE INDEX IF NOT EXISTS idx_roles_organization_name ON roles(IFNULL(organization_id, 0), name);",
+    )?;
+
+    let user_version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if user_version < 1 {
+        normalize_timestamps(conn)?;
+    }
+    if user_version < 2 {
+        // Users that predate roles keep unrestricted keys
+        conn.execute(
+            "INSERT OR IGNORE INTO user_roles (user_id, role_id) SELECT u.id, r.id FROM users u, roles r WHERE r.name = 'admin' AND r.organization_id IS NULL AND NOT EXISTS (SELECT 1 FROM user_roles ur WHERE ur.user_id = u.id)",
+            [],
+        )?;
+    }
+    if user_version < SCHEMA_VERSION {
+        conn.execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION))?;
+    }
+    Ok(())
+}
+
+// Rewrite timestamps stored by older versions (SQLite CURRENT_TIMESTAMP and
+// non-UTC RFC 3339 strings) into the canonical representation.
+fn normalize_timestamps(conn: &Connection) -> Result<(), ApiError> {
+    for (table, column) in TIMESTAMP_COLUMNS {
+        let rows: Vec<(i64, String)> = {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT rowid, {} FROM {} WHERE {} IS NOT NULL",
+                column, table, column
+            ))?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            rows
+        };
+
+        for (rowid, value) in rows {
+            match parse_timestamp(&value) {
+                Some(dt) => {
+                    let canonical = to_db_timestamp(&dt);
+                    if canonical != value {
+                        conn.execute(
+                            &format!("UPDATE {} SET {} = ? WHERE rowid = ?", table, column),
+                            params![canonical, rowid],
+                        )?;
+                    }
+                }
+                None => tracing::warn!(
+                    "Unparseable timestamp {:?} in {}.{} (rowid {})",
+                    value,
+                    table,
+                    column,
+                    rowid
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Service accounts have no email, and SQLite cannot drop the NOT NULL constraint in
+// place, so the users table is copied into one with the current definition
+fn rebuild_users_for_service_accounts(conn: &Connection) -> Result<(), ApiError> {
+    conn.execute_batch(
+        "BEGIN;
+         CREATE TABLE users_new (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             organization_id INTEGER NOT NULL DEFAULT 1,
+             account_type TEXT NOT NULL DEFAULT 'user',
+             team TEXT,
+             username TEXT UNIQUE NOT NULL,
+             email TEXT UNIQUE,
+             created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+             updated_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+             FOREIGN KEY (organization_id) REFERENCES organizations(id)
+         );
+         INSERT INTO users_new (id, organization_id, username, email, created_at, updated_at)
+             SELECT id, organization_id, username, email, created_at, updated_at FROM users;
+         DROP TABLE users;
+         ALTER TABLE users_new RENAME TO users;
+         COMMIT;",
+    )?;
+    Ok(())
+}
+
+// Roles became per organization; the old table made names globally unique.
+// Roles created before then belong to the default organization. Foreign keys
+// are off while the table is swapped so that role assignments survive.
+fn rebuild_roles_for_organizations(conn: &Connection) -> Result<(), ApiError> {
+    conn.execute_batch(
+        "PRAGMA foreign_keys = OFF;
+         BEGIN;
+         CREATE TABLE roles_new (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             organization_id INTEGER,
+             name TEXT NOT NULL,
+             description TEXT NOT NULL,
+             scopes TEXT NOT NULL,
+             created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+             FOREIGN KEY (organization_id) REFERENCES organizations(id)
+         );
+         INSERT INTO roles_new (id, organization_id, name, description, scopes, created_at)
+             SELECT id, CASE WHEN name IN ('viewer', 'developer', 'admin') THEN NULL ELSE 1 END,
+                    name, description, scopes, created_at
+             FROM roles;
+         DROP TABLE roles;
+         ALTER TABLE roles_new RENAME TO roles;
+         COMMIT;
+         PRAGMA foreign_keys = ON;",
+    )?;
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool, ApiError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+    Ok(exists)
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), ApiError> {
+    if !column_exists(conn, table, column)? {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {};",
+            table, column, definition
+        ))?;
+    }
+    Ok(())
 }
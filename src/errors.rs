@@ -1,3 +1,4 @@
+use axum::http::StatusCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -20,25 +21,93 @@ pub enum ApiError {
     #[error("API key is inactive")]
     KeyInactive,
 
+    #[error("API key has been revoked")]
+    KeyRevoked,
+
     #[error("Invalid token")]
     InvalidToken,
 
     #[error("Token expired")]
     TokenExpired,
 
+    #[error("Token has been revoked")]
+    TokenRevoked,
+
+    #[error("Token is not yet valid")]
+    TokenNotYetValid,
+
+    #[error("Token was not issued for this audience")]
+    InvalidAudience,
+
+    #[error("Credential is not valid in this environment")]
+    EnvironmentMismatch,
+
+    #[error("Insufficient scope: missing {0}")]
+    InsufficientScope(String),
+
+    #[error("Refresh token reuse detected; token family revoked")]
+    TokenReuseDetected,
+
     #[error("User not found")]
     UserNotFound,
 
     #[error("User already exists")]
     UserExists,
 
+    #[error("Role not found")]
+    RoleNotFound,
+
+    #[error("Organization not found")]
+    OrganizationNotFound,
+
+    #[error("Service account not found")]
+    ServiceAccountNotFound,
+
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
 
+    #[error("Invalid signing key: {0}")]
+    InvalidSigningKey(String),
+
+    #[error("Signing key not found")]
+    SigningKeyNotFound,
+
     #[error("Internal server error")]
     Internal,
 }
 
+impl ApiError {
+    // HTTP status code to report for this error
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidKeyFormat
+            | ApiError::InvalidChecksum
+            | ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::KeyExpired
+            | ApiError::KeyInactive
+            | ApiError::KeyRevoked
+            | ApiError::InvalidToken
+            | ApiError::TokenExpired
+            | ApiError::TokenRevoked
+            | ApiError::TokenNotYetValid
+            | ApiError::InvalidAudience
+            | ApiError::EnvironmentMismatch
+            | ApiError::TokenReuseDetected => StatusCode::UNAUTHORIZED,
+            ApiError::InsufficientScope(_) => StatusCode::FORBIDDEN,
+            ApiError::KeyNotFound
+            | ApiError::UserNotFound
+            | ApiError::RoleNotFound
+            | ApiError::OrganizationNotFound
+            | ApiError::ServiceAccountNotFound
+            | ApiError::SigningKeyNotFound => StatusCode::NOT_FOUND,
+            ApiError::UserExists => StatusCode::CONFLICT,
+            ApiError::Database(_) | ApiError::InvalidSigningKey(_) | ApiError::Internal => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
 impl From<serde_json::Error> for ApiError {
     fn from(err: serde_json::Error) -> Self {
         ApiError::InvalidRequest(err.to_string())
@@ -46,7 +115,11 @@ impl From<serde_json::Error> for ApiError {
 }
 
 impl From<jsonwebtoken::errors::Error> for ApiError {
-    fn from(_err: jsonwebtoken::errors::Error) -> Self {
-        ApiError::InvalidToken
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => ApiError::TokenExpired,
+            jsonwebtoken::errors::ErrorKind::ImmatureSignature => ApiError::TokenNotYetValid,
+            _ => ApiError::InvalidToken,
+        }
     }
 }
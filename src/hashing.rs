@@ -0,0 +1,391 @@
+// Hashing of stored credentials (API keys, access and refresh tokens).
+//
+// Every stored hash records the scheme that produced it in its `hash_algorithm`
+// column, so the configured algorithm can change without invalidating existing
+// credentials: lookups try every scheme the server can compute, and stale hashes
+// are upgraded on use or by `migrate_credential_hashes`.
+
+use crate::database::Database;
+use crate::errors::ApiError;
+use crate::secret::SecretString;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+// Context string deriving the BLAKE3 key from the pepper
+const BLAKE3_KEY_CONTEXT: &str = "secure-api-key 2024-06 credential hashing";
+
+// Hash algorithms, identified in storage by `id()`. An identifier never changes
+// meaning; a changed construction gets a new identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    // Unkeyed SHA-256, used before keyed hashing existed
+    Sha256,
+    // HMAC-SHA256 keyed with the server pepper
+    HmacSha256,
+    // BLAKE3 in keyed mode, with a key derived from the server pepper
+    Blake3Keyed,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 3] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::HmacSha256,
+        HashAlgorithm::Blake3Keyed,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::HmacSha256 => "hmac-sha256",
+            HashAlgorithm::Blake3Keyed => "blake3-keyed",
+        }
+    }
+
+    // Keyed algorithms need the pepper, and resist offline guessing without it
+    pub fn is_keyed(&self) -> bool {
+        !matches!(self, HashAlgorithm::Sha256)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = ApiError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.id() == id)
+            .ok_or_else(|| ApiError::InvalidRequest(format!("Unknown hash algorithm: {:?}", id)))
+    }
+}
+
+// How a stored hash was computed: one algorithm applied to the credential, or a
+// keyed algorithm applied to a legacy hash of it (`hmac-sha256(sha256)`), which
+// is how hashes are upgraded without knowing the credential
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashScheme {
+    pub algorithm: HashAlgorithm,
+    pub wraps: Option<HashAlgorithm>,
+}
+
+impl HashScheme {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            wraps: None,
+        }
+    }
+
+    pub fn wrapping(algorithm: HashAlgorithm, inner: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            wraps: Some(inner),
+        }
+    }
+}
+
+impl From<HashAlgorithm> for HashScheme {
+    fn from(algorithm: HashAlgorithm) -> Self {
+        HashScheme::new(algorithm)
+    }
+}
+
+impl fmt::Display for HashScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.wraps {
+            Some(inner) => write!(f, "{}({})", self.algorithm, inner),
+            None => write!(f, "{}", self.algorithm),
+        }
+    }
+}
+
+impl FromStr for HashScheme {
+    type Err = ApiError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        match id.strip_suffix(')').and_then(|id| id.split_once('(')) {
+            Some((outer, inner)) => Ok(HashScheme::wrapping(outer.parse()?, inner.parse()?)),
+            None => Ok(HashScheme::new(id.parse()?)),
+        }
+    }
+}
+
+// One hash algorithm. Hashes are lowercase hex.
+pub trait CredentialHasher: Send + Sync {
+    fn algorithm(&self) -> HashAlgorithm;
+    fn hash(&self, secret: &[u8]) -> String;
+}
+
+pub struct Sha256Hasher;
+
+impl CredentialHasher for Sha256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Sha256
+    }
+
+    fn hash(&self, secret: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(secret))
+    }
+}
+
+pub struct HmacSha256Hasher {
+    pepper: Zeroizing<Vec<u8>>,
+}
+
+impl HmacSha256Hasher {
+    pub fn new(pepper: &[u8]) -> Self {
+        Self {
+            pepper: Zeroizing::new(pepper.to_vec()),
+        }
+    }
+}
+
+impl CredentialHasher for HmacSha256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::HmacSha256
+    }
+
+    fn hash(&self, secret: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.pepper).expect("HMAC accepts keys of any length");
+        mac.update(secret);
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+}
+
+pub struct Blake3KeyedHasher {
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl Blake3KeyedHasher {
+    pub fn new(pepper: &[u8]) -> Self {
+        Self {
+            key: Zeroizing::new(blake3::derive_key(BLAKE3_KEY_CONTEXT, pepper)),
+        }
+    }
+}
+
+impl CredentialHasher for Blake3KeyedHasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Blake3Keyed
+    }
+
+    fn hash(&self, secret: &[u8]) -> String {
+        blake3::keyed_hash(&self.key, secret).to_hex().to_string()
+    }
+}
+
+// The algorithm new hashes are computed with, plus every algorithm the server can
+// still verify (all of them when a pepper is configured, SHA-256 otherwise)
+#[derive(Clone)]
+pub struct CredentialHashing {
+    current: HashAlgorithm,
+    hashers: Vec<Arc<dyn CredentialHasher>>,
+}
+
+impl CredentialHashing {
+    // Hash with `current`; keyed algorithms require a pepper
+    pub fn new(current: HashAlgorithm, pepper: Option<&[u8]>) -> Result<Self, ApiError> {
+        let mut hashers: Vec<Arc<dyn CredentialHasher>> = vec![Arc::new(Sha256Hasher)];
+        if let Some(pepper) = pepper {
+            hashers.push(Arc::new(HmacSha256Hasher::new(pepper)));
+            hashers.push(Arc::new(Blake3KeyedHasher::new(pepper)));
+        }
+        if !hashers.iter().any(|hasher| hasher.algorithm() == current) {
+            return Err(ApiError::InvalidRequest(format!(
+                "Hash algorithm {} requires a pepper",
+                current
+            )));
+        }
+        Ok(Self { current, hashers })
+    }
+
+    // Plain SHA-256, for servers without a pepper
+    pub fn unkeyed() -> Self {
+        Self {
+            current: HashAlgorithm::Sha256,
+            hashers: vec![Arc::new(Sha256Hasher)],
+        }
+    }
+
+    // HMAC-SHA256 with `pepper`, still verifying every other algorithm
+    pub fn hmac(pepper: &[u8]) -> Self {
+        Self::new(HashAlgorithm::HmacSha256, Some(pepper))
+            .expect("HMAC-SHA256 is available with a pepper")
+    }
+
+    // Configure from CREDENTIAL_PEPPER and CREDENTIAL_HASH_ALGORITHM (default
+    // hmac-sha256 with a pepper, sha256 without)
+    pub fn from_env() -> Result<Self, ApiError> {
+        let pepper = std::env::var("CREDENTIAL_PEPPER")
+            .ok()
+            .filter(|pepper| !pepper.is_empty())
+            .map(SecretString::new);
+        let algorithm = match std::env::var("CREDENTIAL_HASH_ALGORITHM") {
+            Ok(id) if !id.is_empty() => id.parse()?,
+            _ if pepper.is_some() => HashAlgorithm::HmacSha256,
+            _ => HashAlgorithm::Sha256,
+        };
+        Self::new(
+            algorithm,
+            pepper
+                .as_ref()
+                .map(|pepper| pepper.expose_secret().as_bytes()),
+        )
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.current
+    }
+
+    // Hash a credential for storage with the current algorithm
+    pub fn hash(&self, secret: &str) -> String {
+        self.hasher(self.current)
+            .expect("the current algorithm always has a hasher")
+            .hash(secret.as_bytes())
+    }
+
+    // Hash a credential under `scheme`, or None if the server cannot compute it
+    pub fn hash_with(&self, scheme: HashScheme, secret: &str) -> Option<String> {
+        let outer = self.hasher(scheme.algorithm)?;
+        match scheme.wraps {
+            Some(inner) => {
+                let inner = self.hasher(inner)?.hash(secret.as_bytes());
+                Some(outer.hash(inner.as_bytes()))
+            }
+            None => Some(outer.hash(secret.as_bytes())),
+        }
+    }
+
+    // Upgrade a stored hash without the credential by wrapping it in the current
+    // algorithm. Only unkeyed hashes are wrapped, and only under a keyed algorithm.
+    pub fn wrap(&self, stored_hash: &str, stored: HashScheme) -> Option<(String, HashScheme)> {
+        if stored.wraps.is_some() || stored.algorithm.is_keyed() || !self.current.is_keyed() {
+            return None;
+        }
+        let scheme = HashScheme::wrapping(self.current, stored.algorithm);
+        let hash = self.hasher(self.current)?.hash(stored_hash.as_bytes());
+        Some((hash, scheme))
+    }
+
+    // Look up a stored credential by the hash of `secret`: first under the current
+    // algorithm, then under every other scheme the server can compute. A row only
+    // matches if it was recorded under the scheme that produced the hash.
+    pub fn find<T>(
+        &self,
+        secret: &str,
+        lookup: impl Fn(&str) -> Result<(T, String), ApiError>,
+    ) -> Result<T, ApiError> {
+        let mut last_error = None;
+        for scheme in self.schemes() {
+            let Some(hash) = self.hash_with(scheme, secret) else {
+                continue;
+            };
+            match lookup(&hash) {
+                Ok((stored, stored_scheme)) if stored_scheme == scheme.to_string() => {
+                    return Ok(stored)
+                }
+                Ok(_) => {}
+                Err(ApiError::Database(e)) => return Err(ApiError::Database(e)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or(ApiError::InvalidToken))
+    }
+
+    // Every scheme worth trying, current algorithm first
+    fn schemes(&self) -> Vec<HashScheme> {
+        let mut schemes = vec![HashScheme::new(self.current)];
+        for hasher in &self.hashers {
+            let algorithm = hasher.algorithm();
+            if algorithm != self.current {
+                schemes.push(HashScheme::new(algorithm));
+            }
+            if algorithm.is_keyed() {
+                schemes.push(HashScheme::wrapping(algorithm, HashAlgorithm::Sha256));
+            }
+        }
+        schemes
+    }
+
+    fn hasher(&self, algorithm: HashAlgorithm) -> Option<&Arc<dyn CredentialHasher>> {
+        self.hashers
+            .iter()
+            .find(|hasher| hasher.algorithm() == algorithm)
+    }
+}
+
+impl Default for CredentialHashing {
+    fn default() -> Self {
+        Self::unkeyed()
+    }
+}
+
+// Outcome of `migrate_credential_hashes`, per table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashMigrationReport {
+    pub table: &'static str,
+    // Rows already hashed with the current algorithm
+    pub current: usize,
+    // Legacy hashes wrapped in the current algorithm
+    pub upgraded: usize,
+    // Rows that cannot be upgraded without the credential; re-hashed on next use
+    pub flagged: Vec<i64>,
+}
+
+// Tables holding credential hashes
+pub const CREDENTIAL_TABLES: [&str; 3] = ["api_keys", "access_tokens", "refresh_tokens"];
+
+// Bring every stored hash up to the current algorithm where that is possible
+// without the credential, and flag the rest (`needs_rehash`). With `dry_run`
+// nothing is written.
+pub fn migrate_credential_hashes(
+    db: &Database,
+    hashing: &CredentialHashing,
+    dry_run: bool,
+) -> Result<Vec<HashMigrationReport>, ApiError> {
+    let current = HashScheme::new(hashing.algorithm()).to_string();
+    let mut reports = Vec::new();
+
+    for table in CREDENTIAL_TABLES {
+        let mut report = HashMigrationReport {
+            table,
+            ..HashMigrationReport::default()
+        };
+        for (id, hash, stored) in db.list_credential_hashes(table)? {
+            if stored == current {
+                report.current += 1;
+                continue;
+            }
+            let upgrade = stored
+                .parse::<HashScheme>()
+                .ok()
+                .and_then(|scheme| hashing.wrap(&hash, scheme));
+            match upgrade {
+                Some((hash, scheme)) => {
+                    if !dry_run {
+                        db.update_credential_hash(table, id, &hash, &scheme.to_string())?;
+                    }
+                    report.upgraded += 1;
+                }
+                None => {
+                    if !dry_run {
+                        db.flag_credential_rehash(table, id)?;
+                    }
+                    report.flagged.push(id);
+                }
+            }
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
@@ -1,14 +1,26 @@
 // Secure API Key Management System
 // Main library module
 
+pub mod auth;
+pub mod clock;
 pub mod database;
 pub mod errors;
+pub mod hashing;
 pub mod models;
 pub mod rate_limit;
+pub mod revocation;
+pub mod secret;
 pub mod security;
+pub mod signing;
 
+pub use auth::{AuthError, AuthMethod, AuthenticatedKey, Authenticator};
+pub use clock::{Clock, MockClock, SystemClock};
 pub use database::Database;
 pub use errors::ApiError;
+pub use hashing::{CredentialHasher, CredentialHashing, HashAlgorithm, HashScheme};
 pub use models::*;
 pub use rate_limit::{rate_limit_middleware, RateLimitConfig, RateLimitManager, RateLimiter};
-pub use security::{ApiKeyService, TokenService};
+pub use revocation::RevocationCache;
+pub use secret::SecretString;
+pub use security::{ApiKeyService, KeyFormat, TokenService};
+pub use signing::{Keyring, SigningKey, SigningKeyInfo};
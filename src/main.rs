@@ -1,17 +1,26 @@
 use axum::{
-    routing::post,
+    routing::{delete, get, patch, post},
     Router,
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::Json,
-    extract::State,
+    extract::{Path, Query, State},
     middleware,
 };
 use serde_json::json;
 use std::sync::Arc;
 use secure_api_key::{
-    database::Database,
-    security::{ApiKeyService, TokenService},
-    models::{CreateUserRequest, CreateApiKeyRequest, ValidateTokenRequest},
+    auth::{client_details, AuthenticatedKey, Authenticator},
+    database::{Database, DEFAULT_ORGANIZATION_ID, DEFAULT_PAGE_SIZE},
+    errors::ApiError,
+    hashing::CredentialHashing,
+    security::{key_last_four, ApiKeyService, TokenOptions, TokenService},
+    signing::SigningKey,
+    models::{
+        AddSigningKeyRequest, ApiKey, ApiKeyDetails, ApiKeyFilter, ApiKeyPage, AssignRoleRequest, CreateApiKeyRequest, CreateOrganizationRequest,
+        CreateRoleRequest, CreateServiceAccountRequest, Principal, TransferServiceAccountRequest, CreateTokenRequest, CreateUserRequest,
+        ListApiKeysQuery, ListUsageLogsQuery, RefreshTokenRequest, RegisterScopeRequest, RetireSigningKeyRequest, RevokeApiKeyRequest, RevokeTokenRequest,
+        RotateApiKeyRequest, Scope, ScopeSet, ValidateTokenRequest, is_label_key,
+    },
     rate_limit::{RateLimitManager, rate_limit_middleware},
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -36,29 +45,240 @@ async fn main() {
     // Initialize services
     let api_key_service = ApiKeyService::new(
         db.clone(),
-        "myapp".to_string(),
-        "dev".to_string(),
+        std::env::var("API_KEY_PREFIX").unwrap_or_else(|_| "myapp".to_string()),
+        std::env::var("API_KEY_ENVIRONMENT").unwrap_or_else(|_| "dev".to_string()),
         std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string()),
-    );
+    )
+    .with_accepted_prefixes(env_list("API_KEY_PREFIXES"))
+    .with_environments(env_list("API_KEY_ENVIRONMENTS"))
+    .with_key_format(
+        std::env::var("API_KEY_FORMAT")
+            .map(|format| format.parse().expect("Invalid API_KEY_FORMAT"))
+            .unwrap_or_default(),
+    )
+    .with_rotation_grace_period(chrono::Duration::hours(
+        std::env::var("KEY_ROTATION_GRACE_HOURS")
+            .ok()
+            .and_then(|hours| hours.parse().ok())
+            .unwrap_or(24 * 7),
+    ));
+
+    // Environments this deployment serves: keys of any other environment are
+    // rejected on every route, even though keys may be issued for them
+    let serving_environments = match env_list("API_SERVING_ENVIRONMENTS") {
+        environments if environments.is_empty() => vec![api_key_service.environment().to_string()],
+        environments => environments,
+    };
+    let api_key_service = api_key_service.with_serving_environments(serving_environments.clone());
+
+    // Algorithm and server-side pepper for stored key and token hashes
+    let hashing = CredentialHashing::from_env().expect("Invalid credential hashing configuration");
+    if !hashing.algorithm().is_keyed() {
+        tracing::warn!("Key and token hashes are unkeyed SHA-256; set CREDENTIAL_PEPPER to key them");
+    }
+    let api_key_service = api_key_service.with_hashing(hashing.clone());
+
+    // Optional server-wide cap on API key lifetime
+    let api_key_service = match std::env::var("API_KEY_MAX_LIFETIME_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+    {
+        Some(days) => api_key_service.with_max_key_lifetime(chrono::Duration::days(days)),
+        None => api_key_service,
+    };
 
     let token_service = TokenService::new(
         db.clone(),
         std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string()),
     );
 
+    // Name the shared secret so a later secret can be introduced alongside it
+    let token_service = match std::env::var("JWT_SECRET_KID") {
+        Ok(kid) => {
+            let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
+            token_service.with_signing_key(SigningKey::hmac(kid, secret.as_bytes()))
+        }
+        Err(_) => token_service,
+    };
+
+    // Sign with an asymmetric key when one is configured (RS256, ES256 or EdDSA)
+    let token_service = match std::env::var("JWT_PRIVATE_KEY_PATH") {
+        Ok(path) => {
+            let algorithm = std::env::var("JWT_ALGORITHM")
+                .unwrap_or_else(|_| "RS256".to_string())
+                .parse()
+                .expect("Invalid JWT_ALGORITHM");
+            let signing_key = SigningKey::from_file(algorithm, &path, std::env::var("JWT_KEY_ID").ok())
+                .expect("Failed to load JWT signing key");
+            tracing::info!("Signing tokens with {:?} key {}", signing_key.algorithm(), signing_key.kid());
+            token_service.with_signing_key(signing_key)
+        }
+        Err(_) => token_service,
+    };
+
+    // Issuer, audiences tokens may be minted for, and tolerated clock skew
+    let token_service = token_service
+        .with_issuer(std::env::var("JWT_ISSUER").unwrap_or_else(|_| "secure-api-key".to_string()))
+        .with_audiences(env_list("JWT_AUDIENCES"))
+        .with_leeway(
+            std::env::var("JWT_LEEWAY_SECONDS")
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(60),
+        )
+        .with_access_token_ttl(chrono::Duration::seconds(
+            std::env::var("ACCESS_TOKEN_TTL_SECONDS")
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(60 * 60),
+        ))
+        .with_max_access_token_ttl(chrono::Duration::seconds(
+            std::env::var("ACCESS_TOKEN_MAX_TTL_SECONDS")
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(24 * 60 * 60),
+        ));
+    let token_service = token_service
+        .with_hashing(hashing)
+        .with_serving_environments(serving_environments);
+
+    // Keys that still verify tokens but no longer sign them:
+    // JWT_PREVIOUS_SECRETS="kid:secret,..." and JWT_VERIFICATION_KEY_PATHS="ALG:path,..."
+    let previous_secrets = std::env::var("JWT_PREVIOUS_SECRETS").unwrap_or_default();
+    for entry in previous_secrets.split(',').filter(|entry| !entry.is_empty()) {
+        let (kid, secret) = entry.split_once(':')
+            .expect("JWT_PREVIOUS_SECRETS entries must be kid:secret");
+        token_service.keyring
+            .introduce(SigningKey::hmac(kid.to_string(), secret.as_bytes()))
+            .expect("Failed to add previous JWT secret");
+    }
+    let verification_keys = std::env::var("JWT_VERIFICATION_KEY_PATHS").unwrap_or_default();
+    for entry in verification_keys.split(',').filter(|entry| !entry.is_empty()) {
+        let (algorithm, path) = entry.split_once(':')
+            .expect("JWT_VERIFICATION_KEY_PATHS entries must be ALG:path");
+        let key = SigningKey::from_file(algorithm.parse().expect("Invalid algorithm"), path, None)
+            .expect("Failed to load JWT verification key");
+        token_service.keyring.introduce(key)
+            .expect("Failed to add JWT verification key");
+    }
+
     // Initialize rate limit manager
     let rate_limit_manager = RateLimitManager::new();
 
+    // Checks credentials and scopes on protected routes
+    let authenticator = Authenticator::new(api_key_service.clone(), token_service.clone());
+
     // Create shared state
     let state = Arc::new((db, api_key_service, token_service, rate_limit_manager));
 
     // Create router with rate limiting
     let app = Router::new()
-        .route("/users", post(create_user))
-        .route("/api-keys", post(create_api_key))
+        // User and key creation, within the caller's organization
+        .route(
+            "/users",
+            post(create_user).route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        // Key metadata: owners see their own keys, admins every key of their organization
+        .route(
+            "/users/:id/api-keys",
+            get(list_user_api_keys).route_layer(authenticator.require_scopes(&["read"])),
+        )
+        .route(
+            "/api-keys/:id",
+            get(get_api_key).route_layer(authenticator.require_scopes(&["read"])),
+        )
+        .route(
+            "/api-keys/:id",
+            patch(update_api_key).route_layer(authenticator.require_scopes(&["write"])),
+        )
+        .route(
+            "/api-keys",
+            post(create_api_key).route_layer(authenticator.require_scopes(&["write"])),
+        )
+        // Tenant administration: limited to keys of the caller's organization
+        .route(
+            "/api-keys",
+            get(list_api_keys).route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        .route(
+            "/api-keys/:id",
+            delete(revoke_api_key).route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        .route(
+            "/api-keys/:id/rotate",
+            post(rotate_api_key).route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        .route(
+            "/usage-logs",
+            get(list_usage_logs).route_layer(authenticator.require_scopes(&["admin"])),
+        )
         .route("/validate", post(validate_api_key))
+        .route("/tokens", post(create_token))
+        .route("/tokens/refresh", post(refresh_token))
         .route("/tokens/validate", post(validate_token))
-        .route("/protected", post(protected_endpoint))
+        .route("/tokens/revoke", post(revoke_token))
+        .route(
+            "/protected",
+            post(protected_endpoint).route_layer(authenticator.require_scopes(&["read"])),
+        )
+        .route("/.well-known/jwks.json", get(jwks))
+        // Platform administration spans every organization (see `require_platform_admin`);
+        // whoever controls the signing key can mint a token for any tenant
+        .route(
+            "/admin/signing-keys",
+            get(list_signing_keys)
+                .post(add_signing_key)
+                .route_layer(authenticator.require_scopes(&["platform:admin"])),
+        )
+        .route(
+            "/admin/signing-keys/:kid/promote",
+            post(promote_signing_key).route_layer(authenticator.require_scopes(&["platform:admin"])),
+        )
+        .route(
+            "/admin/signing-keys/:kid/retire",
+            post(retire_signing_key).route_layer(authenticator.require_scopes(&["platform:admin"])),
+        )
+        .route(
+            "/admin/organizations",
+            get(list_organizations)
+                .post(create_organization)
+                .route_layer(authenticator.require_scopes(&["platform:admin"])),
+        )
+        // Service accounts of the caller's organization
+        .route(
+            "/admin/service-accounts",
+            get(list_service_accounts)
+                .post(create_service_account)
+                .route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        .route(
+            "/admin/service-accounts/:id/transfer",
+            post(transfer_service_account).route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        // The registry limits the scopes every key and token of every tenant may carry
+        .route(
+            "/admin/scopes",
+            get(list_scopes)
+                .post(register_scope)
+                .route_layer(authenticator.require_scopes(&["platform:admin"])),
+        )
+        // Role assignment: admins manage the roles of their own organization's users
+        .route(
+            "/admin/roles",
+            get(list_roles)
+                .post(create_role)
+                .route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        .route(
+            "/admin/users/:id/roles",
+            get(list_user_roles)
+                .post(assign_role)
+                .route_layer(authenticator.require_scopes(&["admin"])),
+        )
+        .route(
+            "/admin/users/:id/roles/:role",
+            delete(unassign_role).route_layer(authenticator.require_scopes(&["admin"])),
+        )
         .layer(middleware::from_fn_with_state(
             state.clone(),
             rate_limit_middleware,
@@ -75,58 +295,440 @@ async fn main() {
 
 async fn create_user(
     State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
     Json(payload): Json<CreateUserRequest>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let (db, _, _, _) = &*state;
     
-    match db.create_user(&payload.username, &payload.email) {
+    // Admins add users to their own organization only
+    let organization_id = key.organization_id;
+    match db.create_user_in_organization(organization_id, &payload.username, &payload.email) {
         Ok(user_id) => Ok(Json(json!({
             "success": true,
             "user_id": user_id,
+            "organization_id": organization_id,
             "message": "User created successfully"
         }))),
+        Err(ApiError::OrganizationNotFound) => {
+            Err((StatusCode::NOT_FOUND, ApiError::OrganizationNotFound.to_string()))
+        }
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
 }
 
+async fn create_organization(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Json(payload): Json<CreateOrganizationRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    require_platform_admin(&key)?;
+    if payload.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Organization name is required".to_string()));
+    }
+
+    let organization_id = db.create_organization(&payload.name)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "organization_id": organization_id,
+        "name": payload.name,
+        "message": "Organization created successfully"
+    })))
+}
+
+async fn create_service_account(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Json(payload): Json<CreateServiceAccountRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    if payload.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Service account name is required".to_string()));
+    }
+
+    let organization_id = key.organization_id;
+    let service_account_id = db.create_service_account(organization_id, &payload.name, payload.team.as_deref())
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "service_account_id": service_account_id,
+        "organization_id": organization_id,
+        "name": payload.name,
+        "team": payload.team,
+        "message": "Service account created successfully"
+    })))
+}
+
+async fn list_service_accounts(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    let accounts = db.list_service_accounts(key.organization_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "organization_id": key.organization_id,
+        "service_accounts": accounts
+    })))
+}
+
+async fn transfer_service_account(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(service_account_id): Path<i64>,
+    Json(payload): Json<TransferServiceAccountRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    if payload.team.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Team is required".to_string()));
+    }
+
+    // Accounts of other organizations are reported as not found
+    db.get_service_account(service_account_id)
+        .and_then(|account| {
+            if account.organization_id == key.organization_id {
+                Ok(account)
+            } else {
+                Err(ApiError::ServiceAccountNotFound)
+            }
+        })
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // The account keeps its keys and roles; only the owning team changes
+    db.transfer_service_account(service_account_id, &payload.team)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "service_account_id": service_account_id,
+        "team": payload.team,
+        "message": "Service account transferred successfully"
+    })))
+}
+
+async fn list_organizations(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    require_platform_admin(&key)?;
+    let organizations = db.list_organizations()
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "organizations": organizations
+    })))
+}
+
 async fn create_api_key(
     State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
     Json(payload): Json<CreateApiKeyRequest>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let (db, api_key_service, token_service, _) = &*state;
+
+    // Callers create keys for themselves, or as admins for anyone in their
+    // organization; owners in other organizations are reported as not found
+    db.get_principal_in_organization(key.organization_id, payload.user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    if payload.user_id != key.user_id && !key.has_scope("admin") {
+        let e = ApiError::InsufficientScope("admin".to_string());
+        return Err((e.status_code(), e.to_string()));
+    }
+
+    // Resolve expiry against the server-wide maximum lifetime
+    let expires_at = api_key_service.resolve_expiry(payload.expires_at)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // A key maximum above the server maximum would never apply
+    let server_max_ttl = token_service.max_access_token_ttl.num_seconds();
+    if payload.max_token_ttl_seconds.is_some_and(|seconds| seconds <= 0 || seconds > server_max_ttl) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("max_token_ttl_seconds must be between 1 and {}", server_max_ttl),
+        ));
+    }
+    payload.details.validate()
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // Reject malformed or unregistered scopes and store them normalized
+    let scopes = ScopeSet::parse(&payload.scopes)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    db.check_scopes_registered(&scopes)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // Keys cannot carry scopes beyond what the caller holds or the owner's roles grant
+    let missing = key.scopes.missing(&scopes);
+    if !missing.is_empty() {
+        let e = ApiError::InsufficientScope(missing.to_string());
+        return Err((e.status_code(), e.to_string()));
+    }
+    let missing = db.get_user_role_scopes(payload.user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?
+        .missing(&scopes);
+    if !missing.is_empty() {
+        let e = ApiError::InsufficientScope(missing.to_string());
+        return Err((e.status_code(), e.to_string()));
+    }
+    let scopes = scopes.to_strings();
     
-    // Generate API key
-    let (api_key, key_hash) = api_key_service.generate_api_key()
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Generate API key for the requested (or default) prefix and environment
+    let prefix = payload.prefix.as_deref().unwrap_or(api_key_service.prefix());
+    let environment = payload.environment.as_deref().unwrap_or(api_key_service.environment());
+    let (api_key, key_hash) = api_key_service.generate_api_key_for(prefix, environment)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
     
     // Store in database
-    let key_id = db.create_api_key(
+    let key_id = db.create_api_key_with_hash_algorithm(
         payload.user_id,
         &key_hash,
-        &api_key_service.prefix,
-        &api_key_service.environment,
-        api_key_service.version,
-        &payload.scopes,
-        None,
+        api_key_service.hash_algorithm(),
+        prefix,
+        environment,
+        api_key_service.version(),
+        &scopes,
+        expires_at,
     ).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    db.set_api_key_last_four(key_id, key_last_four(api_key.expose_secret()))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if payload.max_token_ttl_seconds.is_some() {
+        db.set_api_key_max_token_ttl(key_id, payload.max_token_ttl_seconds)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    db.update_api_key_details(key_id, &payload.details)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     // Generate access token
     let access_token = token_service.generate_access_token(
         payload.user_id,
         key_id,
-        payload.scopes,
+        scopes,
     ).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     Ok(Json(json!({
         "success": true,
-        "api_key": api_key,
-        "access_token": access_token,
+        "api_key": api_key.expose_secret(),
+        "api_key_id": key_id,
+        "environment": environment,
+        "name": payload.details.name,
+        "access_token": access_token.expose_secret(),
+        "expires_at": expires_at.map(|dt| dt.to_rfc3339()),
+        "max_token_ttl_seconds": payload.max_token_ttl_seconds,
         "message": "API key created successfully"
     })))
 }
 
+async fn list_api_keys(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Query(query): Query<ListApiKeysQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    let filter = ApiKeyFilter {
+        organization_id: Some(key.organization_id),
+        ..api_key_filter(query)?
+    };
+    let page = db.list_api_keys(&filter)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "organization_id": key.organization_id,
+        "api_keys": api_key_page_metadata(&page),
+        "next_cursor": page.next_cursor
+    })))
+}
+
+async fn list_user_api_keys(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(user_id): Path<i64>,
+    Query(query): Query<ListApiKeysQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    if key.user_id != user_id && !key.has_scope("admin") {
+        let e = ApiError::InsufficientScope("admin".to_string());
+        return Err((e.status_code(), e.to_string()));
+    }
+
+    // Users of other organizations simply have no keys here
+    let filter = ApiKeyFilter {
+        organization_id: Some(key.organization_id),
+        user_id: Some(user_id),
+        ..api_key_filter(query)?
+    };
+    let page = db.list_api_keys(&filter)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "user_id": user_id,
+        "api_keys": api_key_page_metadata(&page),
+        "next_cursor": page.next_cursor
+    })))
+}
+
+async fn get_api_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(key_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    let api_key = visible_api_key(db, &key, key_id)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "api_key": api_key_metadata(&api_key, chrono::Utc::now())
+    })))
+}
+
+async fn update_api_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(key_id): Path<i64>,
+    Json(payload): Json<ApiKeyDetails>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    visible_api_key(db, &key, key_id)?;
+    payload.validate()
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    db.update_api_key_details(key_id, &payload)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    let api_key = db.get_api_key(key_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "api_key": api_key_metadata(&api_key, chrono::Utc::now()),
+        "message": "API key updated successfully"
+    })))
+}
+
+async fn list_usage_logs(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Query(query): Query<ListUsageLogsQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    let logs = db.list_usage_logs(
+        key.organization_id,
+        query.api_key_id,
+        query.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+    ).map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "usage_logs": logs
+    })))
+}
+
+async fn revoke_api_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(key_id): Path<i64>,
+    Json(payload): Json<RevokeApiKeyRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    // Keys of other organizations are reported as not found
+    let target = db.get_api_key_for_organization(key.organization_id, key_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // Revoke the key and cascade to its access tokens, recording who revoked it
+    let revoked_by = key.principal.to_string();
+    let revoked_tokens = db.revoke_api_key(key_id, &payload.reason, Some(&revoked_by))
+        .map_err(|e| match e {
+            ApiError::KeyRevoked => (StatusCode::CONFLICT, e.to_string()),
+            e => (e.status_code(), e.to_string()),
+        })?;
+
+    tracing::info!(
+        "Revoked API key {} {:?} ({} access tokens)",
+        key_id,
+        target.name.as_deref().unwrap_or_default(),
+        revoked_tokens
+    );
+
+    Ok(Json(json!({
+        "success": true,
+        "api_key_id": key_id,
+        "revoked_tokens": revoked_tokens,
+        "message": "API key revoked successfully"
+    })))
+}
+
+async fn rotate_api_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(key_id): Path<i64>,
+    Json(payload): Json<RotateApiKeyRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, api_key_service, token_service, _) = &*state;
+
+    db.get_api_key_for_organization(key.organization_id, key_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // Issue successor key, keeping the old one valid for the grace period
+    let grace_period = payload
+        .grace_period_hours
+        .map(|hours| {
+            chrono::Duration::try_hours(hours).ok_or((
+                StatusCode::BAD_REQUEST,
+                "grace_period_hours is out of range".to_string(),
+            ))
+        })
+        .transpose()?;
+    let rotated = api_key_service.rotate_api_key(key_id, grace_period)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // Generate access token for the new key
+    let access_token = token_service.generate_access_token(
+        rotated.key.user_id,
+        rotated.key.id,
+        rotated.key.scopes.clone(),
+    ).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    tracing::info!(
+        "Rotated API key {} {:?} to {}",
+        rotated.predecessor_id,
+        rotated.key.name.as_deref().unwrap_or_default(),
+        rotated.key.id
+    );
+
+    Ok(Json(json!({
+        "success": true,
+        "api_key": rotated.api_key.expose_secret(),
+        "api_key_id": rotated.key.id,
+        "version": rotated.key.version,
+        "access_token": access_token.expose_secret(),
+        "previous_api_key_id": rotated.predecessor_id,
+        "expires_at": rotated.key.expires_at.map(|dt| dt.to_rfc3339()),
+        "previous_key_valid_until": rotated.rotate_by.to_rfc3339(),
+        "message": "API key rotated successfully"
+    })))
+}
+
 async fn validate_api_key(
     State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    headers: HeaderMap,
     Json(payload): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let (db, api_key_service, _, _) = &*state;
@@ -134,10 +736,35 @@ async fn validate_api_key(
     let api_key = payload["api_key"].as_str()
         .ok_or((StatusCode::BAD_REQUEST, "API key is required".to_string()))?;
 
-    match api_key_service.validate_api_key(api_key) {
+    // Insist on an environment this deployment serves, and on the requested one if given
+    let validated = api_key_service.validate_api_key(api_key).and_then(|api_key_data| {
+        let checked = api_key_service
+            .check_serving_environment(&api_key_data.environment)
+            .and_then(|_| match payload["environment"].as_str() {
+                Some(environment) if environment != api_key_data.environment => {
+                    Err(ApiError::EnvironmentMismatch)
+                }
+                _ => Ok(()),
+            });
+        let (ip_address, user_agent) = client_details(&headers);
+        let _ = db.log_usage(api_key_data.id, None, "/validate", ip_address, user_agent, checked.is_ok());
+        checked.map(|_| api_key_data)
+    });
+
+    match validated {
         Ok(api_key_data) => {
             // Update usage count
             let _ = db.update_api_key_usage(api_key_data.id);
+
+            let deprecation = api_key_data.deprecation_notice();
+            if let Some(notice) = &deprecation {
+                tracing::warn!(
+                    "API key {} {:?} used after rotation: {}",
+                    api_key_data.id,
+                    api_key_data.name.as_deref().unwrap_or_default(),
+                    notice
+                );
+            }
             
             Ok(Json(json!({
                 "success": true,
@@ -145,9 +772,14 @@ async fn validate_api_key(
                 "api_key_data": {
                     "id": api_key_data.id,
                     "user_id": api_key_data.user_id,
+                    "organization_id": api_key_data.organization_id,
+                    "environment": api_key_data.environment,
                     "scopes": api_key_data.scopes,
-                    "is_active": api_key_data.is_active
+                    "is_active": api_key_data.is_active,
+                    "version": api_key_data.version,
+                    "deprecated": deprecation.is_some()
                 },
+                "warning": deprecation,
                 "message": "API key is valid"
             })))
         }
@@ -160,21 +792,111 @@ async fn validate_api_key(
     }
 }
 
+async fn create_token(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    Json(payload): Json<CreateTokenRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, api_key_service, token_service, _) = &*state;
+
+    // Exchange a valid API key for an access token and refresh token
+    let api_key = api_key_service.validate_api_key(payload.api_key.expose_secret())
+        .and_then(|api_key| {
+            api_key_service.check_serving_environment(&api_key.environment).map(|_| api_key)
+        })
+        .map_err(|e| (StatusCode::UNAUTHORIZED, e.to_string()))?;
+    let _ = db.update_api_key_usage(api_key.id);
+
+    let ttl = payload.ttl_seconds
+        .map(|seconds| {
+            chrono::Duration::try_seconds(seconds)
+                .ok_or((StatusCode::BAD_REQUEST, "ttl_seconds is out of range".to_string()))
+        })
+        .transpose()?;
+    let options = TokenOptions {
+        audience: payload.audience,
+        ttl,
+        scopes: payload.scopes,
+    };
+    let tokens = token_service.issue_token_pair_with(&api_key, &options)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "access_token": tokens.access_token.expose_secret(),
+        "token_type": tokens.token_type,
+        "expires_in": tokens.expires_in,
+        "refresh_token": tokens.refresh_token.expose_secret(),
+        "refresh_token_expires_in": tokens.refresh_token_expires_in,
+        "scopes": tokens.scopes,
+        "audience": tokens.audience,
+        "warning": api_key.deprecation_notice(),
+        "message": "Token issued successfully"
+    })))
+}
+
+async fn refresh_token(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, token_service, _) = &*state;
+
+    let tokens = token_service.refresh_access_token(payload.refresh_token.expose_secret())
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "access_token": tokens.access_token.expose_secret(),
+        "token_type": tokens.token_type,
+        "expires_in": tokens.expires_in,
+        "refresh_token": tokens.refresh_token.expose_secret(),
+        "refresh_token_expires_in": tokens.refresh_token_expires_in,
+        "scopes": tokens.scopes,
+        "audience": tokens.audience,
+        "message": "Token refreshed successfully"
+    })))
+}
+
 async fn validate_token(
     State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
     Json(payload): Json<ValidateTokenRequest>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let (_, _, token_service, _) = &*state;
+    let (db, api_key_service, token_service, _) = &*state;
     
-    match token_service.validate_access_token(&payload.token) {
+    let required_scopes = ScopeSet::parse(payload.required_scopes.as_deref().unwrap_or_default())
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    let result = match &payload.audience {
+        Some(audience) => token_service.validate_access_token_for_audience(payload.token.expose_secret(), audience),
+        None => token_service.validate_access_token(payload.token.expose_secret()),
+    }
+    .and_then(|claims| claims.require_scopes(&required_scopes).map(|_| claims))
+    .and_then(|claims| {
+        // Older tokens lack the claim and take their key's environment
+        let environment = match &claims.environment {
+            Some(environment) => environment.clone(),
+            None => db.get_api_key(claims.api_key_id)?.environment,
+        };
+        api_key_service.check_serving_environment(&environment).map(|_| claims)
+    });
+
+    match result {
         Ok(claims) => Ok(Json(json!({
             "success": true,
             "valid": true,
             "claims": {
                 "user_id": claims.sub,
+                "principal": claims.principal().ok(),
                 "api_key_id": claims.api_key_id,
+                // Older tokens lack the claim; report their key's organization
+                "organization_id": claims.organization_id.or_else(|| {
+                    db.get_api_key(claims.api_key_id).ok().map(|api_key| api_key.organization_id)
+                }),
                 "scopes": claims.scopes,
-                "expires_at": claims.exp
+                "issuer": claims.iss,
+                "audience": claims.aud,
+                "not_before": claims.nbf,
+                "expires_at": claims.exp,
+                "jti": claims.jti
             },
             "message": "Token is valid"
         }))),
@@ -187,31 +909,404 @@ async fn validate_token(
     }
 }
 
+async fn revoke_token(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    Json(payload): Json<RevokeTokenRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, token_service, _) = &*state;
+
+    let claims = token_service.revoke_access_token(payload.token.expose_secret())
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    tracing::info!("Revoked access token {} (API key {})", claims.jti, claims.api_key_id);
+
+    Ok(Json(json!({
+        "success": true,
+        "jti": claims.jti,
+        "message": "Token revoked successfully"
+    })))
+}
+
+async fn jwks(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+) -> Json<serde_json::Value> {
+    let (_, _, token_service, _) = &*state;
+
+    // Public keys only; shared secrets are never published
+    Json(json!(token_service.jwks()))
+}
+
+async fn list_scopes(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    require_platform_admin(&key)?;
+
+    let scopes = db.list_scopes()
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "scopes": scopes
+    })))
+}
+
+async fn register_scope(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Json(payload): Json<RegisterScopeRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    require_platform_admin(&key)?;
+
+    let scope = Scope::parse(&payload.name)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    if scope.is_wildcard() {
+        return Err((StatusCode::BAD_REQUEST, "Wildcard scopes cannot be registered".to_string()));
+    }
+
+    let scope_id = db.register_scope(&scope, &payload.description)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "id": scope_id,
+        "name": scope.to_string(),
+        "message": "Scope registered successfully"
+    })))
+}
+
+async fn list_roles(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    let roles = db.list_roles(key.organization_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "roles": roles
+    })))
+}
+
+async fn create_role(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Json(payload): Json<CreateRoleRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    let valid_name = !payload.name.is_empty()
+        && payload.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_name {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid role name: {:?}", payload.name)));
+    }
+
+    let scopes = ScopeSet::parse(&payload.scopes)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    db.check_scopes_registered(&scopes)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    // Roles are created in the organization of the calling admin
+    let role_id = db.create_role(key.organization_id, &payload.name, &payload.description, &scopes)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "id": role_id,
+        "organization_id": key.organization_id,
+        "name": payload.name,
+        "scopes": scopes,
+        "message": "Role created successfully"
+    })))
+}
+
+async fn list_user_roles(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(user_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    db.get_principal_in_organization(key.organization_id, user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    let roles = db.get_user_roles(user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    let scopes = db.get_user_role_scopes(user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "user_id": user_id,
+        "roles": roles,
+        "scopes": scopes
+    })))
+}
+
+async fn assign_role(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(user_id): Path<i64>,
+    Json(payload): Json<AssignRoleRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    // Users of other organizations are reported as not found
+    db.get_principal_in_organization(key.organization_id, user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    db.assign_role(user_id, &payload.role)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    Ok(Json(json!({
+        "success": true,
+        "user_id": user_id,
+        "role": payload.role,
+        "message": "Role assigned successfully"
+    })))
+}
+
+async fn unassign_role(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path((user_id, role)): Path<(i64, String)>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (db, _, _, _) = &*state;
+
+    db.get_principal_in_organization(key.organization_id, user_id)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    // Existing keys of the user are narrowed and their access tokens revoked
+    let removed = db.unassign_role(user_id, &role)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, format!("User {} does not have role {}", user_id, role)));
+    }
+
+    Ok(Json(json!({
+        "success": true,
+        "user_id": user_id,
+        "role": role,
+        "message": "Role removed successfully"
+    })))
+}
+
+async fn list_signing_keys(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, token_service, _) = &*state;
+
+    require_platform_admin(&key)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "keys": token_service.keyring.keys()
+    })))
+}
+
+async fn add_signing_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    caller: AuthenticatedKey,
+    Json(payload): Json<AddSigningKeyRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, token_service, _) = &*state;
+
+    require_platform_admin(&caller)?;
+    let algorithm: jsonwebtoken::Algorithm = payload.algorithm.parse()
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("Unsupported algorithm {}", payload.algorithm)))?;
+
+    let key = match (algorithm, payload.kid, payload.secret, payload.private_key_pem) {
+        (jsonwebtoken::Algorithm::HS256, Some(kid), Some(secret), None) => {
+            SigningKey::hmac(kid, secret.expose_secret().as_bytes())
+        }
+        (jsonwebtoken::Algorithm::HS256, _, _, _) => {
+            return Err((StatusCode::BAD_REQUEST, "HS256 keys require kid and secret".to_string()));
+        }
+        (algorithm, kid, None, Some(pem)) => SigningKey::from_pem(algorithm, pem.expose_secret().as_bytes(), kid)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?,
+        _ => {
+            return Err((StatusCode::BAD_REQUEST, "private_key_pem is required".to_string()));
+        }
+    };
+    let kid = key.kid().to_string();
+
+    // New keys only verify until promoted
+    token_service.keyring.introduce(key)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    tracing::info!("Added JWT verification key {}", kid);
+
+    Ok(Json(json!({
+        "success": true,
+        "kid": kid,
+        "message": "Signing key added"
+    })))
+}
+
+async fn promote_signing_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(kid): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, token_service, _) = &*state;
+
+    require_platform_admin(&key)?;
+    token_service.keyring.promote(&kid)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    tracing::info!("Promoted JWT signing key {}", kid);
+
+    Ok(Json(json!({
+        "success": true,
+        "kid": kid,
+        "message": "Signing key promoted"
+    })))
+}
+
+async fn retire_signing_key(
+    State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
+    key: AuthenticatedKey,
+    Path(kid): Path<String>,
+    Json(payload): Json<RetireSigningKeyRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, token_service, _) = &*state;
+
+    require_platform_admin(&key)?;
+    let retire_at = token_service.retire_signing_key(&kid, payload.retire_at)
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    tracing::info!("JWT signing key {} retires at {}", kid, retire_at.to_rfc3339());
+
+    Ok(Json(json!({
+        "success": true,
+        "kid": kid,
+        "retire_at": retire_at.to_rfc3339(),
+        "message": "Signing key scheduled for retirement"
+    })))
+}
+
 async fn protected_endpoint(
     State(state): State<Arc<(Database, ApiKeyService, TokenService, RateLimitManager)>>,
-    Json(payload): Json<ValidateTokenRequest>,
+    key: AuthenticatedKey,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let (db, _, token_service, _) = &*state;
-    
-    // Validate token
-    let claims = token_service.validate_access_token(&payload.token)
-        .map_err(|e| (StatusCode::UNAUTHORIZED, e.to_string()))?;
-    
-    // Get user information
-    let user_id = claims.sub.parse::<i64>()
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Invalid user ID".to_string()))?;
+    let (db, _, _, _) = &*state;
     
-    let user = db.get_user(user_id)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Credentials and the "read" scope were checked by the route layer
+    let owner = match key.principal {
+        Principal::User(user_id) => {
+            let user = db.get_user(user_id)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            json!({ "user": { "id": user.id, "username": user.username, "email": user.email } })
+        }
+        Principal::Service(service_account_id) => {
+            let account = db.get_service_account(service_account_id)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            json!({ "service_account": { "id": account.id, "name": account.name, "team": account.team } })
+        }
+    };
 
     Ok(Json(json!({
         "success": true,
         "message": "Access granted to protected endpoint",
-        "user": {
-            "id": user.id,
-            "username": user.username,
-            "email": user.email
-        },
-        "scopes": claims.scopes
+        "principal": key.principal,
+        "owner": owner,
+        "api_key_id": key.api_key_id,
+        "organization_id": key.organization_id,
+        "scopes": key.scopes
     })))
 }
+
+fn api_key_filter(query: ListApiKeysQuery) -> Result<ApiKeyFilter, (StatusCode, String)> {
+    let status = query.status.as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|e: ApiError| (e.status_code(), e.to_string()))?;
+
+    let label = query.label
+        .map(|label| match label.split_once(':') {
+            Some((key, value)) if is_label_key(key) => Ok((key.to_string(), value.to_string())),
+            Some((key, _)) => Err((StatusCode::BAD_REQUEST, format!("Invalid label key: {:?}", key))),
+            None => Err((StatusCode::BAD_REQUEST, "label must be key:value".to_string())),
+        })
+        .transpose()?;
+
+    Ok(ApiKeyFilter {
+        status,
+        environment: query.environment,
+        search: query.q.filter(|q| !q.is_empty()),
+        label,
+        cursor: query.cursor,
+        limit: query.limit,
+        ..ApiKeyFilter::default()
+    })
+}
+
+fn api_key_page_metadata(page: &ApiKeyPage) -> Vec<serde_json::Value> {
+    let now = chrono::Utc::now();
+    page.api_keys.iter().map(|api_key| api_key_metadata(api_key, now)).collect()
+}
+
+// Platform administration (organizations, signing keys) reaches every tenant, so
+// `platform:admin` only counts for keys of the default organization, which runs
+// the deployment; tenant admins hold it through `*` within their own organization
+fn require_platform_admin(key: &AuthenticatedKey) -> Result<(), (StatusCode, String)> {
+    if key.organization_id != DEFAULT_ORGANIZATION_ID {
+        let e = ApiError::InsufficientScope("platform:admin".to_string());
+        return Err((e.status_code(), e.to_string()));
+    }
+    Ok(())
+}
+
+// Key of the caller's organization that the caller owns, or any such key for
+// admins; other keys are reported as not found
+fn visible_api_key(db: &Database, key: &AuthenticatedKey, key_id: i64) -> Result<ApiKey, (StatusCode, String)> {
+    db.get_api_key_for_organization(key.organization_id, key_id)
+        .and_then(|api_key| {
+            if api_key.user_id == key.user_id || key.has_scope("admin") {
+                Ok(api_key)
+            } else {
+                Err(ApiError::KeyNotFound)
+            }
+        })
+        .map_err(|e| (e.status_code(), e.to_string()))
+}
+
+// Everything about a key except the secret and its hash
+fn api_key_metadata(api_key: &ApiKey, now: chrono::DateTime<chrono::Utc>) -> serde_json::Value {
+    json!({
+        "id": api_key.id,
+        "user_id": api_key.user_id,
+        "masked_key": api_key.masked_key(),
+        "name": api_key.name,
+        "description": api_key.description,
+        "labels": api_key.labels,
+        "environment": api_key.environment,
+        "version": api_key.version,
+        "scopes": api_key.scopes,
+        "status": api_key.status(now),
+        "is_active": api_key.is_active,
+        "issued_at": api_key.issued_at.to_rfc3339(),
+        "expires_at": api_key.expires_at.map(|dt| dt.to_rfc3339()),
+        "last_used_at": api_key.last_used_at.map(|dt| dt.to_rfc3339()),
+        "usage_count": api_key.usage_count,
+        "revoked_at": api_key.revoked_at.map(|dt| dt.to_rfc3339()),
+        "rotate_by": api_key.rotate_by.map(|dt| dt.to_rfc3339()),
+        "predecessor_id": api_key.predecessor_id
+    })
+}
+
+// Comma-separated list from an environment variable; empty when unset
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .map(|value| {
+            value.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -1,19 +1,88 @@
+use crate::errors::ApiError;
+use crate::secret::SecretString;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Tenant owning users and their keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Organization {
+    pub id: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
+    pub organization_id: i64,
     pub username: String,
     pub email: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+// Non-human principal (CI pipeline, microservice) that owns keys on behalf of a team.
+// Service accounts share the id space of users, so roles and keys refer to them by user id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceAccount {
+    pub id: i64,
+    pub organization_id: i64,
+    pub name: String,
+    pub team: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Owner of an API key, as written to the `sub` claim: a user's id on its own
+// (unchanged from earlier tokens) or `service:<id>` for a service account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum Principal {
+    User(i64),
+    Service(i64),
+}
+
+impl Principal {
+    pub fn id(&self) -> i64 {
+        match self {
+            Principal::User(id) | Principal::Service(id) => *id,
+        }
+    }
+
+    pub fn is_service(&self) -> bool {
+        matches!(self, Principal::Service(_))
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::User(id) => write!(f, "{}", id),
+            Principal::Service(id) => write!(f, "service:{}", id),
+        }
+    }
+}
+
+impl FromStr for Principal {
+    type Err = ApiError;
+
+    fn from_str(sub: &str) -> Result<Self, Self::Err> {
+        let parsed = match sub.strip_prefix("service:") {
+            Some(id) => id.parse().map(Principal::Service),
+            None => sub.parse().map(Principal::User),
+        };
+        parsed.map_err(|_| ApiError::InvalidToken)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiKey {
     pub id: i64,
     pub user_id: i64,
+    pub organization_id: i64,
     pub key_hash: String,
     pub key_prefix: String,
     pub environment: String,
@@ -24,22 +93,439 @@ pub struct ApiKey {
     pub expires_at: Option<DateTime<Utc>>,
     pub last_used_at: Option<DateTime<Utc>>,
     pub usage_count: i64,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revoked_by: Option<String>,
+    pub revocation_reason: Option<String>,
+    pub predecessor_id: Option<i64>,
+    pub rotate_by: Option<DateTime<Utc>>,
+    pub max_token_ttl_seconds: Option<i64>,
+    // Last characters of the key, shown to help users tell keys apart
+    pub key_last_four: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    // Algorithm `key_hash` was computed with ("sha256", "hmac-sha256")
+    pub hash_algorithm: String,
+}
+
+impl ApiKey {
+    // Check whether the key may be used at `now` (not revoked, active, inside any
+    // rotation grace period and not expired)
+    pub fn check_usable(&self, now: DateTime<Utc>) -> Result<(), ApiError> {
+        if self.revoked_at.is_some() {
+            return Err(ApiError::KeyRevoked);
+        }
+        if !self.is_active || self.rotate_by.is_some_and(|rotate_by| now >= rotate_by) {
+            return Err(ApiError::KeyInactive);
+        }
+        if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(ApiError::KeyExpired);
+        }
+        Ok(())
+    }
+
+    // Lifecycle state at `now`, decided in the same order as `check_usable`
+    pub fn status(&self, now: DateTime<Utc>) -> ApiKeyStatus {
+        if self.revoked_at.is_some() {
+            ApiKeyStatus::Revoked
+        } else if !self.is_active || self.rotate_by.is_some_and(|rotate_by| now >= rotate_by) {
+            ApiKeyStatus::Inactive
+        } else if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            ApiKeyStatus::Expired
+        } else if self.rotate_by.is_some() {
+            ApiKeyStatus::Rotating
+        } else {
+            ApiKeyStatus::Active
+        }
+    }
+
+    // Displayable form of the key that cannot be used to authenticate
+    // (`myapp_prod_****ABCD`; keys created before hints were stored show no suffix)
+    pub fn masked_key(&self) -> String {
+        format!(
+            "{}_{}_****{}",
+            self.key_prefix,
+            self.environment,
+            self.key_last_four.as_deref().unwrap_or_default()
+        )
+    }
+
+    // Warning reported for a key that has been rotated but is still inside its grace period
+    pub fn deprecation_notice(&self) -> Option<String> {
+        self.rotate_by
+            .map(|rotate_by| format!("deprecated, rotate by {}", rotate_by.to_rfc3339()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyStatus {
+    Active,
+    // Rotated, but still inside its grace period
+    Rotating,
+    Expired,
+    Inactive,
+    Revoked,
+}
+
+impl ApiKeyStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyStatus::Active => "active",
+            ApiKeyStatus::Rotating => "rotating",
+            ApiKeyStatus::Expired => "expired",
+            ApiKeyStatus::Inactive => "inactive",
+            ApiKeyStatus::Revoked => "revoked",
+        }
+    }
+}
+
+impl fmt::Display for ApiKeyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ApiKeyStatus {
+    type Err = ApiError;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "active" => Ok(ApiKeyStatus::Active),
+            "rotating" => Ok(ApiKeyStatus::Rotating),
+            "expired" => Ok(ApiKeyStatus::Expired),
+            "inactive" => Ok(ApiKeyStatus::Inactive),
+            "revoked" => Ok(ApiKeyStatus::Revoked),
+            _ => Err(ApiError::InvalidRequest(format!(
+                "Invalid status: {:?} (expected active, rotating, expired, inactive or revoked)",
+                status
+            ))),
+        }
+    }
+}
+
+// Human-readable details of a key. Fields left out are not changed; an empty
+// name or description clears it, and `labels` replaces every label.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApiKeyDetails {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+}
+
+impl ApiKeyDetails {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.name.as_ref().is_some_and(|name| name.chars().count() > 100) {
+            return Err(ApiError::InvalidRequest(
+                "Name must be at most 100 characters".to_string(),
+            ));
+        }
+        if self
+            .description
+            .as_ref()
+            .is_some_and(|description| description.chars().count() > 1000)
+        {
+            return Err(ApiError::InvalidRequest(
+                "Description must be at most 1000 characters".to_string(),
+            ));
+        }
+        for (key, value) in self.labels.iter().flatten() {
+            if !is_label_key(key) {
+                return Err(ApiError::InvalidRequest(format!(
+                    "Invalid label key: {:?}",
+                    key
+                )));
+            }
+            if value.chars().count() > 255 {
+                return Err(ApiError::InvalidRequest(format!(
+                    "Label {:?} must be at most 255 characters",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Label keys are short identifiers (`team`, `app.kubernetes.io/name`) so they can
+// be used in `label=key:value` searches
+pub fn is_label_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 63
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '/'))
+}
+
+// Criteria for listing keys. Results are ordered by id; `cursor` is the id of the
+// last key of the previous page.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeyFilter {
+    pub organization_id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub status: Option<ApiKeyStatus>,
+    pub environment: Option<String>,
+    // Case-insensitive text matched against the name and description
+    pub search: Option<String>,
+    // Label that must be present with exactly this value
+    pub label: Option<(String, String)>,
+    pub cursor: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKeyPage {
+    pub api_keys: Vec<ApiKey>,
+    // Pass as `cursor` to fetch the next page; None on the last page
+    pub next_cursor: Option<i64>,
+}
+
+// Built-in actions, each implying the ones before it (`admin` implies `write` implies `read`)
+const ACTION_HIERARCHY: [&str; 3] = ["read", "write", "admin"];
+
+// A single permission: `action`, `resource:action`, `resource:*` or `*`.
+// An action without a resource applies to every resource, so `read` grants
+// `billing:read`, and `*` grants everything.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Scope {
+    resource: Option<String>,
+    action: String,
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Result<Self, ApiError> {
+        let invalid = || ApiError::InvalidRequest(format!("Invalid scope: {:?}", scope));
+        let is_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        };
+
+        let (resource, action) = match scope.split_once(':') {
+            Some((resource, action)) => (Some(resource), action),
+            None => (None, scope),
+        };
+        if resource.is_some_and(|resource| !is_name(resource)) {
+            return Err(invalid());
+        }
+        if action != "*" && !is_name(action) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            resource: resource.map(str::to_string),
+            action: action.to_string(),
+        })
+    }
+
+    pub fn resource(&self) -> Option<&str> {
+        self.resource.as_deref()
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.action == "*"
+    }
+
+    // Whether holding this scope is enough to satisfy `required`
+    pub fn implies(&self, required: &Scope) -> bool {
+        if self.resource.is_some() && self.resource != required.resource {
+            return false;
+        }
+        if self.is_wildcard() || self.action == required.action {
+            return true;
+        }
+        let rank = |action: &str| ACTION_HIERARCHY.iter().position(|a| *a == action);
+        matches!(
+            (rank(&self.action), rank(&required.action)),
+            (Some(granted), Some(required)) if granted > required
+        )
+    }
+
+    // The broadest scope implied by both `self` and `other`, if any
+    // (`billing:*` and `write` meet at `billing:write`)
+    pub fn meet(&self, other: &Scope) -> Option<Scope> {
+        let resource = match (&self.resource, &other.resource) {
+            (Some(a), Some(b)) if a != b => return None,
+            (Some(resource), _) | (None, Some(resource)) => Some(resource.clone()),
+            (None, None) => None,
+        };
+
+        let rank = |action: &str| ACTION_HIERARCHY.iter().position(|a| *a == action);
+        let action = if self.is_wildcard() {
+            other.action.clone()
+        } else if other.is_wildcard() || self.action == other.action {
+            self.action.clone()
+        } else {
+            match (rank(&self.action), rank(&other.action)) {
+                (Some(a), Some(b)) => ACTION_HIERARCHY[a.min(b)].to_string(),
+                _ => return None,
+            }
+        };
+
+        Some(Scope { resource, action })
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.resource {
+            Some(resource) => write!(f, "{}:{}", resource, self.action),
+            None => f.write_str(&self.action),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ApiError;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        Scope::parse(scope)
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = ApiError;
+
+    fn try_from(scope: String) -> Result<Self, Self::Error> {
+        Scope::parse(&scope)
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+// The scopes granted to a key or token, without duplicates
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ScopeSet(Vec<Scope>);
+
+impl ScopeSet {
+    pub fn parse<S: AsRef<str>>(scopes: &[S]) -> Result<Self, ApiError> {
+        scopes
+            .iter()
+            .map(|scope| Scope::parse(scope.as_ref()))
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Scope> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Whether any held scope implies `required`
+    pub fn grants(&self, required: &Scope) -> bool {
+        self.0.iter().any(|scope| scope.implies(required))
+    }
+
+    // Required scopes not granted by this set
+    pub fn missing(&self, required: &ScopeSet) -> ScopeSet {
+        required
+            .iter()
+            .filter(|scope| !self.grants(scope))
+            .cloned()
+            .collect()
+    }
+
+    pub fn satisfies(&self, required: &ScopeSet) -> bool {
+        required.iter().all(|scope| self.grants(scope))
+    }
+
+    // Scopes granted by both sets
+    pub fn intersect(&self, other: &ScopeSet) -> ScopeSet {
+        self.iter()
+            .flat_map(|scope| other.iter().filter_map(move |o| scope.meet(o)))
+            .collect()
+    }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.0.iter().map(Scope::to_string).collect()
+    }
+}
+
+impl FromIterator<Scope> for ScopeSet {
+    fn from_iter<I: IntoIterator<Item = Scope>>(iter: I) -> Self {
+        let mut scopes = Vec::new();
+        for scope in iter {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        ScopeSet(scopes)
+    }
+}
+
+impl fmt::Display for ScopeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_strings().join(" "))
+    }
+}
+
+// Named bundle of scopes assigned to users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub id: i64,
+    pub organization_id: Option<i64>, // None for built-in roles
+    pub name: String,
+    pub description: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Entry in the scope registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredScope {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccessToken {
     pub id: i64,
     pub api_key_id: i64,
+    pub jti: Option<String>,
     pub token_hash: String,
     pub issued_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
     pub is_revoked: bool,
+    pub hash_algorithm: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub api_key_id: i64,
+    pub family_id: String,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub is_revoked: bool,
+    pub audience: Option<String>,
+    pub access_token_ttl_seconds: Option<i64>,
+    pub hash_algorithm: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsageLog {
     pub id: i64,
     pub api_key_id: i64,
+    // Name of the key at the time the log is read, to recognise it at a glance
+    pub api_key_name: Option<String>,
     pub access_token_id: Option<i64>,
     pub endpoint: String,
     pub ip_address: Option<String>,
@@ -49,17 +535,68 @@ pub struct UsageLog {
 }
 
 // Request/Response models
+// Users are created in the organization of the calling admin
 #[derive(Debug, Deserialize)]
 pub struct CreateUserRequest {
     pub username: String,
     pub email: String,
 }
 
+// Service accounts are created in the organization of the calling admin
+#[derive(Debug, Deserialize)]
+pub struct CreateServiceAccountRequest {
+    pub name: String,
+    pub team: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferServiceAccountRequest {
+    pub team: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOrganizationRequest {
+    pub name: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateApiKeyRequest {
     pub user_id: i64,
     pub scopes: Vec<String>,
+    // Defaults to the server's default environment and prefix
+    pub environment: Option<String>,
+    pub prefix: Option<String>,
     pub expires_at: Option<DateTime<Utc>>,
+    pub max_token_ttl_seconds: Option<i64>,
+    #[serde(flatten)]
+    pub details: ApiKeyDetails,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListApiKeysQuery {
+    pub status: Option<String>,
+    pub environment: Option<String>,
+    pub q: Option<String>,
+    // `key:value`
+    pub label: Option<String>,
+    pub cursor: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListUsageLogsQuery {
+    pub api_key_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeApiKeyRequest {
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotateApiKeyRequest {
+    pub grace_period_hours: Option<i64>,
 }
 
 #[derive(Debug, Serialize)]
@@ -69,9 +606,77 @@ pub struct ApiKeyResponse {
     pub expires_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
+pub struct CreateTokenRequest {
+    pub api_key: SecretString,
+    pub audience: Option<String>,
+    pub ttl_seconds: Option<i64>,
+    // Subset of the key's scopes to issue the tokens with
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: SecretString,
+}
+
+// Access token plus the single-use refresh token that replaces it
+#[derive(Debug)]
+pub struct TokenPair {
+    pub access_token: SecretString,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: SecretString,
+    pub refresh_token_expires_in: i64,
+    pub scopes: Vec<String>,
+    pub audience: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
 pub struct ValidateTokenRequest {
-    pub token: String,
+    pub token: SecretString,
+    // Require the token to have been issued for this audience
+    pub audience: Option<String>,
+    // Require the token to grant each of these scopes
+    pub required_scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeTokenRequest {
+    pub token: SecretString,
+}
+
+// Add a JWT verification key: a PEM private key for RS256/ES256/EdDSA, or a secret for HS256.
+// Key files are only loaded from the server's own configuration, never from a request.
+#[derive(Debug, Deserialize)]
+pub struct AddSigningKeyRequest {
+    pub algorithm: String,
+    pub kid: Option<String>,
+    pub private_key_pem: Option<SecretString>,
+    pub secret: Option<SecretString>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterScopeRequest {
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoleRequest {
+    pub name: String,
+    pub description: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssignRoleRequest {
+    pub role: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetireSigningKeyRequest {
+    pub retire_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -3,12 +3,13 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use axum::{
     extract::State,
-    http::{Request, StatusCode},
+    http::{Method, Request, StatusCode},
     middleware::Next,
     response::Response,
     body::Body,
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 // 型エイリアスを定義して循環参照を避ける
 type AppState = Arc<(crate::database::Database, crate::security::ApiKeyService, crate::security::TokenService, RateLimitManager)>;
@@ -192,8 +193,8 @@ pub async fn rate_limit_middleware(
     request: Request<Body>,
     next: Next,
 ) -> Result<Response, (StatusCode, String)> {
-    // クライアント識別子を取得（IPアドレスまたはAPIキー）
-    let identifier = extract_client_identifier(&request);
+    // クライアント識別子を取得（検証済みの資格情報またはIPアドレス）
+    let identifier = extract_client_identifier(&request, &state);
     
     // パスに基づいてレート制限カテゴリを決定
     let category = determine_rate_limit_category(&request);
@@ -225,26 +226,43 @@ pub async fn rate_limit_middleware(
 }
 
 // クライアント識別子を抽出する関数
-fn extract_client_identifier(request: &Request<Body>) -> String {
-    // まずAPIキーを確認
+//
+// 資格情報はSHA-256ハッシュで識別し、生のトークンやAPIキーをレートリミッターに保持しない。
+// 検証できた資格情報だけを識別子にし、無効な資格情報を変えるたびに新しいバケットが
+// 割り当てられないようにする
+pub fn extract_client_identifier(request: &Request<Body>, state: &AppState) -> String {
+    let (_, api_key_service, token_service, _) = &**state;
+
+    // まず検証済みのアクセストークンまたはAPIキーを確認
     if let Some(auth_header) = request.headers().get("Authorization") {
         if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let token = &auth_str[7..];
-                return format!("api_key:{}", token);
+            if let Some(token) = auth_str.strip_prefix("Bearer ") {
+                if token_service.validate_access_token(token).is_ok() {
+                    return credential_identifier(token);
+                }
+            }
+        }
+    } else if let Some(api_key) = request.headers().get(crate::auth::API_KEY_HEADER) {
+        if let Ok(api_key) = api_key.to_str() {
+            if api_key_service.validate_api_key(api_key).is_ok() {
+                return credential_identifier(api_key);
             }
         }
     }
 
-    // APIキーがない場合はIPアドレスを使用
+    // 資格情報がないか無効な場合はIPアドレスを使用
     if let Some(forwarded_for) = request.headers().get("X-Forwarded-For") {
         if let Ok(ip) = forwarded_for.to_str() {
             return format!("ip:{}", ip.split(',').next().unwrap_or(ip).trim());
         }
     }
 
-    // デフォルトはリモートアドレス
-    "unknown".to_string()
+    // IPアドレスも分からない未認証のリクエストは共有のバケットを使う
+    "unauthenticated".to_string()
+}
+
+fn credential_identifier(credential: &str) -> String {
+    format!("api_key:{:x}", Sha256::digest(credential.as_bytes()))
 }
 
 // パスに基づいてレート制限カテゴリを決定する関数
@@ -252,11 +270,21 @@ fn determine_rate_limit_category(request: &Request<Body>) -> String {
     let path = request.uri().path();
     
     match path {
+        "/api-keys" if request.method() == Method::GET => "read".to_string(),
         "/api-keys" => "api_key_gen".to_string(),
         "/users" => "write".to_string(),
         "/validate" => "auth".to_string(),
+        "/tokens" => "auth".to_string(),
+        "/tokens/refresh" => "auth".to_string(),
         "/tokens/validate" => "auth".to_string(),
+        "/tokens/revoke" => "auth".to_string(),
         "/protected" => "read".to_string(),
+        "/.well-known/jwks.json" => "read".to_string(),
+        p if p.starts_with("/api-keys/") && p.ends_with("/rotate") => "api_key_gen".to_string(),
+        p if p.starts_with("/api-keys/") && request.method() == Method::GET => "read".to_string(),
+        p if p.starts_with("/users/") => "read".to_string(),
+        p if p.starts_with("/api-keys/") => "write".to_string(),
+        p if p.starts_with("/admin/") => "write".to_string(),
         _ => "default".to_string(),
     }
 }
@@ -0,0 +1,82 @@
+use crate::database::Database;
+use crate::errors::ApiError;
+use chrono::Utc;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// In-memory set of revoked access token IDs (jti), refreshed from the database.
+//
+// Revocations written through the shared `Database` handle are picked up on the
+// next check via the database's revocation epoch; revocations made by other
+// processes are picked up by the periodic refresh.
+#[derive(Clone)]
+pub struct RevocationCache {
+    db: Database,
+    refresh_interval: Duration,
+    state: Arc<RwLock<CacheState>>,
+}
+
+struct CacheState {
+    revoked: HashSet<String>,
+    refreshed_at: Option<Instant>,
+    epoch: u64,
+}
+
+impl RevocationCache {
+    pub fn new(db: Database, refresh_interval: Duration) -> Self {
+        Self {
+            db,
+            refresh_interval,
+            state: Arc::new(RwLock::new(CacheState {
+                revoked: HashSet::new(),
+                refreshed_at: None,
+                epoch: 0,
+            })),
+        }
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> Result<bool, ApiError> {
+        {
+            let state = self.state.read().unwrap();
+            if !self.is_stale(&state) {
+                return Ok(state.revoked.contains(jti));
+            }
+        }
+
+        self.refresh()?;
+        Ok(self.state.read().unwrap().revoked.contains(jti))
+    }
+
+    // Reload the revocation list from the database
+    pub fn refresh(&self) -> Result<(), ApiError> {
+        // Read the epoch first so a revocation racing with the reload triggers another one
+        let epoch = self.db.revocation_epoch();
+        let revoked: HashSet<String> = self
+            .db
+            .get_revoked_token_ids(Utc::now())?
+            .into_iter()
+            .collect();
+
+        let mut state = self.state.write().unwrap();
+        state.revoked = revoked;
+        state.refreshed_at = Some(Instant::now());
+        state.epoch = epoch;
+        Ok(())
+    }
+
+    // Record a revocation locally without waiting for the next refresh
+    pub fn insert(&self, jti: &str) {
+        self.state.write().unwrap().revoked.insert(jti.to_string());
+    }
+
+    fn is_stale(&self, state: &CacheState) -> bool {
+        match state.refreshed_at {
+            Some(refreshed_at) => {
+                refreshed_at.elapsed() >= self.refresh_interval
+                    || state.epoch != self.db.revocation_epoch()
+            }
+            None => true,
+        }
+    }
+}
@@ -1,171 +1,782 @@
+use crate::clock::{Clock, SystemClock};
 use crate::database::Database;
 use crate::errors::ApiError;
-use crate::models::ApiKey;
+use crate::hashing::CredentialHashing;
+use crate::models::{ApiKey, Principal, RefreshToken, ScopeSet, TokenPair};
+use crate::revocation::RevocationCache;
+use crate::secret::SecretString;
+use crate::signing::{Keyring, SigningKey};
 use base32;
-use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: String, // user_id
+    pub iss: String, // issuer
+    pub sub: String, // user_id, or service:<id> for service accounts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>, // audience the token was issued for
     pub api_key_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization_id: Option<i64>, // tenant that owns the key; absent in older tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>, // environment of the key; absent in older tokens
     pub scopes: Vec<String>,
     pub exp: i64, // expiration time
+    pub nbf: i64, // not before
     pub iat: i64, // issued at
+    pub jti: String, // JWT ID, ties the token to its access_tokens row
 }
 
+impl Claims {
+    // Owner of the key the token was issued from
+    pub fn principal(&self) -> Result<Principal, ApiError> {
+        self.sub.parse()
+    }
+
+    pub fn scope_set(&self) -> Result<ScopeSet, ApiError> {
+        ScopeSet::parse(&self.scopes)
+    }
+
+    // Fail unless the token grants every required scope, directly or by implication
+    pub fn require_scopes(&self, required: &ScopeSet) -> Result<(), ApiError> {
+        let missing = self.scope_set()?.missing(required);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::InsufficientScope(missing.to_string()))
+        }
+    }
+}
+
+// Options for exchanging an API key for tokens
+#[derive(Debug, Clone, Default)]
+pub struct TokenOptions {
+    // Restrict the tokens to one of the configured audiences
+    pub audience: Option<String>,
+    // Requested access token lifetime, clamped to the server and per-key maximums
+    pub ttl: Option<Duration>,
+    // Narrower scopes for the tokens; must be granted by the API key. Defaults to all key scopes.
+    pub scopes: Option<Vec<String>>,
+}
+
+// Trailing characters of a key kept for display in listings (see `ApiKey::masked_key`)
+pub fn key_last_four(api_key: &str) -> &str {
+    &api_key[api_key.len().saturating_sub(4)..]
+}
+
+// Compare secrets (checksums, hashes) in time independent of where they differ.
+// Only a length mismatch returns early, and lengths are not secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+// Digits of base62-encoded key parts
+const BASE62_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// A v2 key's random part is 256 bits, 43 base62 digits; its checksum 6 digits
+const V2_RANDOM_BYTES: usize = 32;
+const V2_RANDOM_LEN: usize = 43;
+const V2_CHECKSUM_LEN: usize = 6;
+// Longest time a rotated key may keep working next to its successor
+const MAX_ROTATION_GRACE_DAYS: i64 = 365;
+
+// Layout of issued API keys. Keys of both formats are accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyFormat {
+    // prefix_env_v1_<timestamp>_<base32 160-bit random>_<base32 SHA-256 checksum>
+    V1,
+    // prefix_env_v2_<base62 256-bit random>_<base62 CRC32>, checkable offline (see `check_v2_key`)
+    #[default]
+    V2,
+}
+
+// Segment after the environment: the format, followed by `r<version>` for keys
+// past version 1, e.g. `v2` or `v2r3` for the third version of a key
+fn format_marker(format: &str, version: i32) -> String {
+    if version > 1 {
+        format!("{}r{}", format, version)
+    } else {
+        format.to_string()
+    }
+}
+
+fn is_format_marker(segment: &str, format: &str) -> bool {
+    match segment.strip_prefix(format) {
+        Some("") => true,
+        Some(version) => version
+            .strip_prefix('r')
+            .is_some_and(|version| {
+                version.bytes().all(|c| c.is_ascii_digit())
+                    && version.parse::<i32>().is_ok_and(|version| version > 1)
+            }),
+        None => false,
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = ApiError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "v1" => Ok(KeyFormat::V1),
+            "v2" => Ok(KeyFormat::V2),
+            _ => Err(ApiError::InvalidRequest(format!(
+                "Unknown key format: {}",
+                format
+            ))),
+        }
+    }
+}
+
+// Check the layout and checksum of a v2 key without the server, whatever its
+// prefix and environment, as clients and secret scanners can: the last segment is
+// the CRC32 of everything before the final `_`, as 6 zero-padded base62 digits
+pub fn check_v2_key(key: &str) -> Result<(), ApiError> {
+    let (payload, checksum) = key.rsplit_once('_').ok_or(ApiError::InvalidKeyFormat)?;
+    let parts: Vec<&str> = payload.split('_').collect();
+    let well_formed = parts.len() == 4
+        && !parts[0].is_empty()
+        && !parts[1].is_empty()
+        && is_format_marker(parts[2], "v2")
+        && parts[3].len() == V2_RANDOM_LEN
+        && is_base62(parts[3])
+        && checksum.len() == V2_CHECKSUM_LEN
+        && is_base62(checksum);
+    if !well_formed {
+        return Err(ApiError::InvalidKeyFormat);
+    }
+
+    let expected = v2_checksum(payload);
+    if !constant_time_eq(checksum.as_bytes(), expected.as_bytes()) {
+        return Err(ApiError::InvalidChecksum);
+    }
+    Ok(())
+}
+
+// Validate a v1 key split on `_`: version, timestamp and SHA-256 checksum
+fn validate_v1_key(parts: &[&str]) -> Result<(), ApiError> {
+    let (prefix, env, version_str, timestamp_str, random_part, checksum_part) =
+        (parts[0], parts[1], parts[2], parts[3], parts[4], parts[5]);
+
+    // Validate timestamp
+    timestamp_str
+        .parse::<u32>()
+        .map_err(|_| ApiError::InvalidKeyFormat)?;
+
+    // Validate checksum
+    let random_bytes = base32::decode(base32::Alphabet::RFC4648 { padding: false }, random_part)
+        .ok_or(ApiError::InvalidKeyFormat)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(prefix.as_bytes());
+    hasher.update(env.as_bytes());
+    hasher.update(version_str.as_bytes());
+    hasher.update(timestamp_str.as_bytes());
+    hasher.update(&random_bytes);
+    let expected_checksum = hasher.finalize();
+
+    let provided_checksum =
+        base32::decode(base32::Alphabet::RFC4648 { padding: false }, checksum_part)
+            .ok_or(ApiError::InvalidChecksum)?;
+
+    if !constant_time_eq(&provided_checksum, &expected_checksum[..4]) {
+        return Err(ApiError::InvalidChecksum);
+    }
+
+    Ok(())
+}
+
+// Format: prefix_env_v2_random_checksum, or prefix_env_v2r<version>_random_checksum
+fn generate_v2_key(prefix: &str, environment: &str, version: i32) -> String {
+    // Generate 32 bytes (256 bits) of random data
+    let mut random_bytes = Zeroizing::new([0u8; V2_RANDOM_BYTES]);
+    rand::thread_rng().fill(&mut *random_bytes);
+
+    let random_part = Zeroizing::new(base62_encode(&*random_bytes, V2_RANDOM_LEN));
+    let payload = Zeroizing::new(format!(
+        "{}_{}_{}_{}",
+        prefix,
+        environment,
+        format_marker("v2", version),
+        *random_part
+    ));
+    format!("{}_{}", *payload, v2_checksum(&payload))
+}
+
+fn v2_checksum(payload: &str) -> String {
+    let crc = crc32fast::hash(payload.as_bytes());
+    base62_encode(&crc.to_be_bytes(), V2_CHECKSUM_LEN)
+}
+
+fn is_base62(value: &str) -> bool {
+    value.bytes().all(|c| c.is_ascii_alphanumeric())
+}
+
+// Big-endian bytes as base62, left-padded with zeros to `width` digits
+fn base62_encode(bytes: &[u8], width: usize) -> String {
+    let mut number = Zeroizing::new(bytes.to_vec());
+    let mut digits = Zeroizing::new(Vec::with_capacity(width));
+    while number.iter().any(|&byte| byte != 0) {
+        // Long division of the whole number by 62
+        let mut remainder = 0u32;
+        for byte in number.iter_mut() {
+            let value = (remainder << 8) | u32::from(*byte);
+            *byte = (value / 62) as u8;
+            remainder = value % 62;
+        }
+        digits.push(BASE62_ALPHABET[remainder as usize]);
+    }
+    while digits.len() < width {
+        digits.push(b'0');
+    }
+    digits.iter().rev().map(|&digit| digit as char).collect()
+}
+
+// Result of rotating an API key
+pub struct RotatedApiKey {
+    pub api_key: SecretString,
+    pub key: ApiKey,
+    pub predecessor_id: i64,
+    pub rotate_by: DateTime<Utc>,
+}
+
+// Configured through the `with_*` builders; clones share the secret rather than copy it
 #[derive(Clone)]
 pub struct ApiKeyService {
-    pub db: Database,
-    pub prefix: String,
-    pub environment: String,
-    pub version: i32,
-    pub secret_key: String,
+    db: Database,
+    // Prefix and environment of keys issued without an explicit choice
+    prefix: String,
+    environment: String,
+    // Every prefix and environment keys may be issued for and validated in
+    accepted_prefixes: Vec<String>,
+    environments: Vec<String>,
+    // Environments whose keys may call this deployment; every issuable one when unset
+    serving_environments: Option<Vec<String>>,
+    version: i32,
+    // Layout of newly issued keys
+    key_format: KeyFormat,
+    secret_key: Arc<SecretString>,
+    rotation_grace_period: Duration,
+    max_key_lifetime: Option<Duration>,
+    // Algorithms stored key hashes are computed and verified with
+    hashing: CredentialHashing,
+    clock: Arc<dyn Clock>,
 }
 
 impl ApiKeyService {
     pub fn new(db: Database, prefix: String, environment: String, secret_key: String) -> Self {
         Self {
             db,
+            accepted_prefixes: vec![prefix.clone()],
+            environments: vec![environment.clone()],
+            serving_environments: None,
             prefix,
             environment,
             version: 1,
-            secret_key,
+            key_format: KeyFormat::default(),
+            secret_key: Arc::new(SecretString::new(secret_key)),
+            rotation_grace_period: Duration::days(7),
+            max_key_lifetime: None,
+            hashing: CredentialHashing::unkeyed(),
+            clock: Arc::new(SystemClock),
         }
     }
 
-    // Generate 160-bit API key
-    pub fn generate_api_key(&self) -> Result<(String, String), ApiError> {
+    // Use a custom time source (tests use a MockClock)
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn db(&self) -> &Database {
+        &self.db
+    }
+
+    // Prefix of keys issued without an explicit choice
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // Environment of keys issued without an explicit choice
+    pub fn environment(&self) -> &str {
+        &self.environment
+    }
+
+    // Stored version of newly issued keys; each rotation of a key increments it
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn secret_key(&self) -> &SecretString {
+        &self.secret_key
+    }
+
+    // Issue keys in this format (v2 by default); keys of either format stay valid
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    pub fn key_format(&self) -> KeyFormat {
+        self.key_format
+    }
+
+    // Cap the lifetime of newly issued keys; keys without an explicit expiry get this lifetime
+    pub fn with_max_key_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_key_lifetime = Some(max_lifetime);
+        self
+    }
+
+    // Hash keys with HMAC-SHA256 keyed with `pepper`. Keys stored with plain SHA-256
+    // keep working and are re-hashed the next time they are validated.
+    pub fn with_pepper(mut self, pepper: Vec<u8>) -> Self {
+        self.hashing = CredentialHashing::hmac(&Zeroizing::new(pepper));
+        self
+    }
+
+    // Hash keys with the given algorithms; see `CredentialHashing::new`
+    pub fn with_hashing(mut self, hashing: CredentialHashing) -> Self {
+        self.hashing = hashing;
+        self
+    }
+
+    // Algorithm newly issued keys are hashed with
+    pub fn hash_algorithm(&self) -> &'static str {
+        self.hashing.algorithm().id()
+    }
+
+    // Also accept these prefixes (e.g. keys issued before a product rename).
+    //
+    // Panics if a prefix contains `_`, which separates the parts of a key
+    pub fn with_accepted_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        for prefix in prefixes {
+            assert!(!prefix.contains('_'), "Invalid key prefix: {:?}", prefix);
+            if !self.accepted_prefixes.contains(&prefix) {
+                self.accepted_prefixes.push(prefix);
+            }
+        }
+        self
+    }
+
+    // Also issue and accept keys for these environments.
+    //
+    // Panics if an environment contains `_`, which separates the parts of a key
+    pub fn with_environments(mut self, environments: Vec<String>) -> Self {
+        for environment in environments {
+            assert!(!environment.contains('_'), "Invalid environment: {:?}", environment);
+            if !self.environments.contains(&environment) {
+                self.environments.push(environment);
+            }
+        }
+        self
+    }
+
+    // Only let keys of these environments, and tokens issued from them, call this
+    // deployment, so a `test` key never passes on a server serving `prod`. Keys of
+    // other environments can still be issued, for the deployments serving them.
+    pub fn with_serving_environments(mut self, environments: Vec<String>) -> Self {
+        self.serving_environments = Some(environments);
+        self
+    }
+
+    pub fn serving_environments(&self) -> Option<&[String]> {
+        self.serving_environments.as_deref()
+    }
+
+    // Fail unless keys of `environment` may call this deployment
+    pub fn check_serving_environment(&self, environment: &str) -> Result<(), ApiError> {
+        match &self.serving_environments {
+            Some(environments) if !environments.iter().any(|env| env == environment) => {
+                Err(ApiError::EnvironmentMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Set how long a rotated key keeps working after its successor is issued
+    pub fn with_rotation_grace_period(mut self, grace_period: Duration) -> Self {
+        self.rotation_grace_period = grace_period;
+        self
+    }
+
+    // Generate an API key with the default prefix and environment
+    pub fn generate_api_key(&self) -> Result<(SecretString, String), ApiError> {
+        self.generate_api_key_for(&self.prefix, &self.environment)
+    }
+
+    // Generate an API key for one of the accepted prefixes and environments
+    pub fn generate_api_key_for(
+        &self,
+        prefix: &str,
+        environment: &str,
+    ) -> Result<(SecretString, String), ApiError> {
+        if !self.accepted_prefixes.iter().any(|accepted| accepted == prefix) {
+            return Err(ApiError::InvalidRequest(format!("Unknown key prefix: {}", prefix)));
+        }
+        if !self.environments.iter().any(|accepted| accepted == environment) {
+            return Err(ApiError::InvalidRequest(format!(
+                "Unknown environment: {}",
+                environment
+            )));
+        }
+        self.generate_key(prefix, environment, self.version)
+    }
+
+    // Generate an API key of the given version in the configured format and its hash
+    fn generate_key(
+        &self,
+        prefix: &str,
+        environment: &str,
+        version: i32,
+    ) -> Result<(SecretString, String), ApiError> {
+        let key_string = match self.key_format {
+            KeyFormat::V1 => self.generate_v1_key(prefix, environment, version),
+            KeyFormat::V2 => generate_v2_key(prefix, environment, version),
+        };
+
+        // Hash the key for storage
+        let key_string = SecretString::new(key_string);
+        let key_hash = self.hashing.hash(key_string.expose_secret());
+
+        Ok((key_string, key_hash))
+    }
+
+    fn generate_v1_key(&self, prefix: &str, environment: &str, version: i32) -> String {
         let mut rng = rand::thread_rng();
 
         // Generate 20 bytes (160 bits) of random data
-        let mut random_bytes = [0u8; 20];
-        rng.fill(&mut random_bytes);
+        let mut random_bytes = Zeroizing::new([0u8; 20]);
+        rng.fill(&mut *random_bytes);
 
         // Create timestamp (32 bits)
-        let timestamp = Utc::now().timestamp() as u32;
+        let timestamp = self.clock.now().timestamp() as u32;
 
         // Calculate checksum (first 4 bytes of SHA256)
+        let marker = format_marker("v1", version);
         let mut hasher = Sha256::new();
-        hasher.update(&self.prefix.as_bytes());
-        hasher.update(&self.environment.as_bytes());
-        hasher.update(&format!("v{}", self.version).as_bytes());
-        hasher.update(&timestamp.to_string().as_bytes());
-        hasher.update(&random_bytes);
+        hasher.update(prefix.as_bytes());
+        hasher.update(environment.as_bytes());
+        hasher.update(marker.as_bytes());
+        hasher.update(timestamp.to_string().as_bytes());
+        hasher.update(*random_bytes);
         let checksum = hasher.finalize();
 
-        // Format: prefix_env_version_timestamp_random_checksum
-        let key_string = format!(
-            "{}_{}_v{}_{}_{}_{}",
-            self.prefix,
-            self.environment,
-            self.version,
+        // Format: prefix_env_v1_timestamp_random_checksum, or v1r<version> in place of v1
+        format!(
+            "{}_{}_{}_{}_{}_{}",
+            prefix,
+            environment,
+            marker,
             timestamp,
-            base32::encode(base32::Alphabet::RFC4648 { padding: false }, &random_bytes),
+            base32::encode(base32::Alphabet::RFC4648 { padding: false }, &*random_bytes),
             base32::encode(base32::Alphabet::RFC4648 { padding: false }, &checksum[..4])
-        );
-
-        // Hash the key for storage
-        let mut hasher = Sha256::new();
-        hasher.update(key_string.as_bytes());
-        let key_hash = format!("{:x}", hasher.finalize());
-
-        Ok((key_string, key_hash))
+        )
     }
 
-    // Validate API key format (simplified without checksum)
+    // Validate API key format and checksum, for keys of either format
     pub fn validate_api_key_format(&self, key: &str) -> Result<(), ApiError> {
         let parts: Vec<&str> = key.split('_').collect();
-        if parts.len() != 6 {
-            return Err(ApiError::InvalidKeyFormat);
-        }
-
-        let (prefix, env, version_str, timestamp_str, random_part, checksum_part) =
-            (parts[0], parts[1], parts[2], parts[3], parts[4], parts[5]);
 
         // Validate prefix and environment
-        if prefix != self.prefix || env != self.environment {
+        if parts.len() < 3
+            || !self.accepted_prefixes.iter().any(|accepted| accepted == parts[0])
+            || !self.environments.iter().any(|accepted| accepted == parts[1])
+        {
             return Err(ApiError::InvalidKeyFormat);
         }
 
-        // Validate version
-        if !version_str.starts_with('v') {
-            return Err(ApiError::InvalidKeyFormat);
+        // The segment after the environment names the format and the key version.
+        // v1 keys issued by rotation used to carry their version alone (`v2`, `v3`, ...).
+        match parts[2] {
+            marker if parts.len() == 5 && is_format_marker(marker, "v2") => check_v2_key(key),
+            version if version.starts_with('v') && parts.len() == 6 => validate_v1_key(&parts),
+            _ => Err(ApiError::InvalidKeyFormat),
         }
+    }
 
-        // Validate timestamp
-        timestamp_str
-            .parse::<u32>()
-            .map_err(|_| ApiError::InvalidKeyFormat)?;
+    // Validate API key and return stored data
+    pub fn validate_api_key(&self, key: &str) -> Result<ApiKey, ApiError> {
+        // Validate format
+        self.validate_api_key_format(key)?;
 
-        // Validate checksum
-        let random_bytes =
-            base32::decode(base32::Alphabet::RFC4648 { padding: false }, random_part)
-                .ok_or(ApiError::InvalidKeyFormat)?;
+        // Get from database, by the current hash or one stored by an older algorithm
+        let mut api_key = self.hashing.find(key, |key_hash| {
+            let api_key = self.db.get_api_key_by_hash(key_hash)?;
+            // Don't rely on SQLite's comparison alone to decide a secret matched
+            if !constant_time_eq(api_key.key_hash.as_bytes(), key_hash.as_bytes()) {
+                return Err(ApiError::KeyNotFound);
+            }
+            let algorithm = api_key.hash_algorithm.clone();
+            Ok((api_key, algorithm))
+        })?;
 
-        let mut hasher = Sha256::new();
-        hasher.update(prefix.as_bytes());
-        hasher.update(env.as_bytes());
-        hasher.update(version_str.as_bytes());
-        hasher.update(timestamp_str.as_bytes());
-        hasher.update(&random_bytes);
-        let expected_checksum = hasher.finalize();
+        let now = self.clock.now();
+
+        // Deactivate rotated keys once their grace period is over
+        if api_key.is_active && api_key.rotate_by.is_some_and(|rotate_by| now >= rotate_by) {
+            self.db.deactivate_api_key(api_key.id)?;
+        }
 
-        let provided_checksum =
-            base32::decode(base32::Alphabet::RFC4648 { padding: false }, checksum_part)
-                .ok_or(ApiError::InvalidChecksum)?;
+        // Check revocation, active flag, rotation grace period and expiration
+        api_key.check_usable(now)?;
 
-        if provided_checksum != expected_checksum[..4] {
-            return Err(ApiError::InvalidChecksum);
+        // Now that the key is known to be good, upgrade a hash stored by an older algorithm
+        let hash_algorithm = self.hash_algorithm();
+        if api_key.hash_algorithm != hash_algorithm {
+            let key_hash = self.hashing.hash(key);
+            self.db
+                .update_api_key_hash(api_key.id, &key_hash, hash_algorithm)?;
+            api_key.key_hash = key_hash;
+            api_key.hash_algorithm = hash_algorithm.to_string();
         }
 
-        Ok(())
+        // Report only the scopes the owner's roles still grant
+        api_key.scopes = self.db.get_effective_scopes(&api_key)?.to_strings();
+
+        Ok(api_key)
     }
 
-    // Validate API key and return stored data
-    pub fn validate_api_key(&self, key: &str) -> Result<ApiKey, ApiError> {
-        // Validate format
-        self.validate_api_key_format(key)?;
+    // Work out the expiry to store for a new key from the requested one and the server maximum
+    pub fn resolve_expiry(
+        &self,
+        requested: Option<DateTime<Utc>>,
+    ) -> Result<Option<DateTime<Utc>>, ApiError> {
+        let now = self.clock.now();
 
-        // Hash the key
-        let mut hasher = Sha256::new();
-        hasher.update(key.as_bytes());
-        let key_hash = format!("{:x}", hasher.finalize());
+        if let Some(expires_at) = requested {
+            if expires_at <= now {
+                return Err(ApiError::InvalidRequest(
+                    "expires_at must be in the future".to_string(),
+                ));
+            }
+        }
 
-        // Get from database
-        let api_key = self.db.get_api_key_by_hash(&key_hash)?;
+        match (requested, self.max_key_lifetime) {
+            (None, Some(max_lifetime)) => Ok(Some(now + max_lifetime)),
+            (Some(expires_at), Some(max_lifetime)) if expires_at > now + max_lifetime => {
+                Err(ApiError::InvalidRequest(format!(
+                    "expires_at exceeds the maximum key lifetime of {} days",
+                    max_lifetime.num_days()
+                )))
+            }
+            (requested, _) => Ok(requested),
+        }
+    }
 
-        // Check if active
-        if !api_key.is_active {
+    // Issue a successor key with the same owner and scopes and a bumped version.
+    // The old key stays valid for the grace period and is then deactivated.
+    pub fn rotate_api_key(
+        &self,
+        key_id: i64,
+        grace_period: Option<Duration>,
+    ) -> Result<RotatedApiKey, ApiError> {
+        let current = self.db.get_api_key(key_id)?;
+        if current.revoked_at.is_some() {
+            return Err(ApiError::KeyRevoked);
+        }
+        if !current.is_active {
             return Err(ApiError::KeyInactive);
         }
 
-        // Check expiration (disabled for now)
-        // if let Some(expires_at) = api_key.expires_at {
-        //     if Utc::now() > expires_at {
-        //         return Err(ApiError::KeyExpired);
-        //     }
-        // }
+        let grace_period = grace_period.unwrap_or(self.rotation_grace_period);
+        if grace_period < Duration::zero() {
+            return Err(ApiError::InvalidRequest(
+                "Grace period must not be negative".to_string(),
+            ));
+        }
+        if grace_period > Duration::days(MAX_ROTATION_GRACE_DAYS) {
+            return Err(ApiError::InvalidRequest(format!(
+                "Grace period must be at most {} days",
+                MAX_ROTATION_GRACE_DAYS
+            )));
+        }
+        let rotate_by = self
+            .clock
+            .now()
+            .checked_add_signed(grace_period)
+            .ok_or_else(|| ApiError::InvalidRequest("Grace period is out of range".to_string()))?;
+        let expires_at = self.resolve_expiry(None)?;
 
-        // Update usage (disabled for now to avoid date parsing issues)
-        // self.db.update_api_key_usage(api_key.id)?;
+        // The successor keeps the prefix and environment of the key it replaces
+        // and carries its bumped version in the key string
+        let version = current.version + 1;
+        let (api_key, key_hash) = self.generate_key(&current.key_prefix, &current.environment, version)?;
+        let new_key_id = self.db.rotate_api_key(
+            current.id,
+            &key_hash,
+            self.hash_algorithm(),
+            version,
+            expires_at,
+            rotate_by,
+        )?;
+        self.db
+            .set_api_key_last_four(new_key_id, key_last_four(api_key.expose_secret()))?;
 
-        Ok(api_key)
+        Ok(RotatedApiKey {
+            api_key,
+            key: self.db.get_api_key(new_key_id)?,
+            predecessor_id: current.id,
+            rotate_by,
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct TokenService {
     pub db: Database,
-    pub secret_key: String,
+    pub keyring: Keyring,
+    pub revocations: RevocationCache,
+    pub refresh_token_ttl: Duration,
+    pub access_token_ttl: Duration,
+    pub max_access_token_ttl: Duration,
+    pub issuer: String,
+    pub audiences: Vec<String>,
+    pub leeway: u64,
+    // Algorithms stored token hashes are computed and verified with
+    pub hashing: CredentialHashing,
+    // Environments whose keys may refresh tokens here; None accepts any
+    pub serving_environments: Option<Vec<String>>,
 }
 
 impl TokenService {
     pub fn new(db: Database, secret_key: String) -> Self {
-        Self { db, secret_key }
+        let secret_key = SecretString::new(secret_key);
+        let revocations = RevocationCache::new(db.clone(), std::time::Duration::from_secs(30));
+        Self {
+            db,
+            keyring: Keyring::new(SigningKey::hmac(
+                "default".to_string(),
+                secret_key.expose_secret().as_bytes(),
+            )),
+            revocations,
+            refresh_token_ttl: Duration::days(30),
+            access_token_ttl: Duration::hours(1),
+            max_access_token_ttl: Duration::hours(24),
+            issuer: "secure-api-key".to_string(),
+            audiences: Vec::new(),
+            leeway: 60,
+            hashing: CredentialHashing::unkeyed(),
+            serving_environments: None,
+        }
+    }
+
+    // Only refresh token families of keys issued for one of these environments
+    pub fn with_serving_environments(mut self, environments: Vec<String>) -> Self {
+        self.serving_environments = Some(environments);
+        self
+    }
+
+    // Hash stored access and refresh tokens with HMAC-SHA256 keyed with `pepper`.
+    // Refresh tokens stored with plain SHA-256 can still be exchanged.
+    pub fn with_pepper(mut self, pepper: Vec<u8>) -> Self {
+        self.hashing = CredentialHashing::hmac(&Zeroizing::new(pepper));
+        self
+    }
+
+    // Hash stored tokens with the given algorithms; see `CredentialHashing::new`
+    pub fn with_hashing(mut self, hashing: CredentialHashing) -> Self {
+        self.hashing = hashing;
+        self
+    }
+
+    // Set the lifetime of access tokens when none is requested
+    pub fn with_access_token_ttl(mut self, ttl: Duration) -> Self {
+        self.access_token_ttl = ttl;
+        self
+    }
+
+    // Set the longest access token lifetime any request may get
+    pub fn with_max_access_token_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_access_token_ttl = max_ttl;
+        self
+    }
+
+    // Work out an access token lifetime: the requested one (or the server default),
+    // clamped to the server maximum and the key's own maximum
+    pub fn resolve_access_token_ttl(
+        &self,
+        api_key: &ApiKey,
+        requested: Option<Duration>,
+    ) -> Result<Duration, ApiError> {
+        if requested.is_some_and(|ttl| ttl <= Duration::zero()) {
+            return Err(ApiError::InvalidRequest("ttl must be positive".to_string()));
+        }
+
+        let mut ttl = requested
+            .unwrap_or(self.access_token_ttl)
+            .min(self.max_access_token_ttl);
+        // A stored maximum too large for a Duration is above the server maximum anyway
+        if let Some(key_max) = api_key.max_token_ttl_seconds.and_then(Duration::try_seconds) {
+            ttl = ttl.min(key_max);
+        }
+        Ok(ttl)
+    }
+
+    // Set the iss claim written to and required of every token
+    pub fn with_issuer(mut self, issuer: String) -> Self {
+        self.issuer = issuer;
+        self
+    }
+
+    // Set the audiences tokens may be issued for
+    pub fn with_audiences(mut self, audiences: Vec<String>) -> Self {
+        self.audiences = audiences;
+        self
+    }
+
+    // Set the clock skew in seconds tolerated when checking exp and nbf
+    pub fn with_leeway(mut self, leeway: u64) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    // Sign tokens with the given key instead of the shared secret
+    pub fn with_signing_key(mut self, signing_key: SigningKey) -> Self {
+        self.keyring = Keyring::new(signing_key);
+        self
+    }
+
+    // Use a keyring set up elsewhere (e.g. with extra verification keys)
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    // Public keys for verifying issued tokens; shared secrets are never included
+    pub fn jwks(&self) -> JwkSet {
+        self.keyring.jwks()
+    }
+
+    // Retire a verification key. By default it keeps verifying until every token
+    // it may have signed has expired.
+    pub fn retire_signing_key(
+        &self,
+        kid: &str,
+        retire_at: Option<DateTime<Utc>>,
+    ) -> Result<DateTime<Utc>, ApiError> {
+        let retire_at = retire_at.unwrap_or_else(|| Utc::now() + self.max_access_token_ttl);
+        self.keyring.retire(kid, retire_at)?;
+        Ok(retire_at)
+    }
+
+    // Set how long an unused refresh token stays valid
+    pub fn with_refresh_token_ttl(mut self, ttl: Duration) -> Self {
+        self.refresh_token_ttl = ttl;
+        self
+    }
+
+    // Set how often the revocation list is reloaded from the database
+    pub fn with_revocation_refresh_interval(mut self, interval: std::time::Duration) -> Self {
+        self.revocations = RevocationCache::new(self.db.clone(), interval);
+        self
     }
 
     // Generate JWT access token
@@ -174,58 +785,313 @@ impl TokenService {
         user_id: i64,
         api_key_id: i64,
         scopes: Vec<String>,
-    ) -> Result<String, ApiError> {
+    ) -> Result<SecretString, ApiError> {
+        let api_key = self.db.get_api_key(api_key_id)?;
+        let scopes = self.authorize_scopes(&api_key, &scopes)?;
+        let ttl = self.resolve_access_token_ttl(&api_key, None)?;
+        let principal = self.db.get_principal(user_id)?;
+        self.issue_access_token(principal, &api_key, scopes, None, None, ttl)
+            .map(|(token, _)| token)
+    }
+
+    // Sign an access token and record it, optionally as part of a refresh token family
+    fn issue_access_token(
+        &self,
+        principal: Principal,
+        api_key: &ApiKey,
+        scopes: Vec<String>,
+        audience: Option<&str>,
+        family_id: Option<&str>,
+        ttl: Duration,
+    ) -> Result<(SecretString, DateTime<Utc>), ApiError> {
         let now = Utc::now();
-        let expires_at = now + Duration::hours(1); // 1 hour expiration
+        let expires_at = now + ttl;
+        let jti = uuid::Uuid::new_v4().to_string();
 
         let claims = Claims {
-            sub: user_id.to_string(),
-            api_key_id,
+            iss: self.issuer.clone(),
+            sub: principal.to_string(),
+            aud: audience.map(str::to_string),
+            api_key_id: api_key.id,
+            organization_id: Some(api_key.organization_id),
+            environment: Some(api_key.environment.clone()),
             scopes,
             exp: expires_at.timestamp(),
+            nbf: now.timestamp(),
             iat: now.timestamp(),
+            jti: jti.clone(),
         };
 
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(self.secret_key.as_ref()),
-        )?;
+        let signing_key = self.keyring.signing_key();
+        let mut header = Header::new(signing_key.algorithm());
+        header.kid = Some(signing_key.kid().to_string());
+
+        let token = SecretString::new(encode(&header, &claims, signing_key.encoding_key())?);
 
         // Store token hash in database
-        let mut hasher = Sha256::new();
-        hasher.update(token.as_bytes());
-        let token_hash = format!("{:x}", hasher.finalize());
+        let token_hash = self.hashing.hash(token.expose_secret());
 
-        self.db
-            .create_access_token(api_key_id, &token_hash, expires_at)?;
+        self.db.create_access_token(
+            api_key.id,
+            &jti,
+            family_id,
+            &token_hash,
+            self.hashing.algorithm().id(),
+            expires_at,
+        )?;
 
-        Ok(token)
+        Ok((token, expires_at))
     }
 
-    // Validate JWT access token
-    pub fn validate_access_token(&self, token: &str) -> Result<Claims, ApiError> {
-        let token_data = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(self.secret_key.as_ref()),
-            &Validation::default(),
-        )?;
+    // Normalize the scopes requested for a token, rejecting malformed or unregistered
+    // scopes and any the API key does not grant (tokens can only be downscoped).
+    // The key grants only what its owner's roles allow.
+    fn authorize_scopes(
+        &self,
+        api_key: &ApiKey,
+        requested: &[String],
+    ) -> Result<Vec<String>, ApiError> {
+        let requested = ScopeSet::parse(requested)?;
+        self.db.check_scopes_registered(&requested)?;
+
+        let missing = self.db.get_effective_scopes(api_key)?.missing(&requested);
+        if !missing.is_empty() {
+            return Err(ApiError::InsufficientScope(missing.to_string()));
+        }
+        Ok(requested.to_strings())
+    }
+
+    // Exchange a validated API key for an access token and a new refresh token family
+    pub fn issue_token_pair(&self, api_key: &ApiKey) -> Result<TokenPair, ApiError> {
+        self.issue_token_pair_with(api_key, &TokenOptions::default())
+    }
+
+    // Exchange a validated API key for tokens restricted by the given options
+    pub fn issue_token_pair_with(
+        &self,
+        api_key: &ApiKey,
+        options: &TokenOptions,
+    ) -> Result<TokenPair, ApiError> {
+        if let Some(audience) = &options.audience {
+            if !self.audiences.contains(audience) {
+                return Err(ApiError::InvalidRequest(format!(
+                    "Unknown audience: {}",
+                    audience
+                )));
+            }
+        }
+
+        // Reject bad requests before recording anything
+        self.resolve_access_token_ttl(api_key, options.ttl)?;
+        let scopes = match &options.scopes {
+            Some(scopes) => self.authorize_scopes(api_key, scopes)?,
+            None => self.db.get_effective_scopes(api_key)?.to_strings(),
+        };
 
-        // Check expiration from JWT claims
-        let now = Utc::now().timestamp();
-        if token_data.claims.exp < now {
+        let family_id = uuid::Uuid::new_v4().to_string();
+        self.issue_token_pair_in_family(
+            api_key,
+            scopes,
+            options.audience.as_deref(),
+            options.ttl,
+            &family_id,
+        )
+    }
+
+    // Exchange a refresh token for a new token pair. Each refresh token is single-use:
+    // presenting one a second time revokes every token in its family.
+    pub fn refresh_access_token(&self, refresh_token: &str) -> Result<TokenPair, ApiError> {
+        let stored: RefreshToken = self.hashing.find(refresh_token, |token_hash| {
+            let stored = self.db.get_refresh_token_by_hash(token_hash)?;
+            if !constant_time_eq(stored.token_hash.as_bytes(), token_hash.as_bytes()) {
+                return Err(ApiError::InvalidToken);
+            }
+            let algorithm = stored.hash_algorithm.clone();
+            Ok((stored, algorithm))
+        })?;
+
+        if stored.is_revoked {
+            return Err(ApiError::TokenRevoked);
+        }
+
+        // Refuse families of keys this deployment does not serve without using up the token
+        let api_key = self.db.get_api_key(stored.api_key_id)?;
+        if let Some(environments) = &self.serving_environments {
+            if !environments.contains(&api_key.environment) {
+                return Err(ApiError::EnvironmentMismatch);
+            }
+        }
+
+        if !self.db.mark_refresh_token_used(stored.id)? {
+            let revoked = self.db.revoke_token_family(&stored.family_id)?;
+            tracing::warn!(
+                "Refresh token reuse detected for API key {}; revoked family {} ({} access tokens)",
+                stored.api_key_id,
+                stored.family_id,
+                revoked
+            );
+            return Err(ApiError::TokenReuseDetected);
+        }
+
+        if Utc::now() >= stored.expires_at {
             return Err(ApiError::TokenExpired);
         }
 
-        // Optional: Check if token is revoked in database (skip for now to avoid date parsing issues)
-        // let mut hasher = Sha256::new();
-        // hasher.update(token.as_bytes());
-        // let token_hash = format!("{:x}", hasher.finalize());
-        // let access_token = self.db.get_access_token_by_hash(&token_hash)?;
-        // if access_token.is_revoked {
-        //     return Err(ApiError::InvalidToken);
-        // }
+        // The issuing key must still be usable
+        api_key.check_usable(Utc::now())?;
+
+        // Narrow the family's scopes if the key or its owner's roles lost any
+        let scopes = ScopeSet::parse(&stored.scopes)?
+            .intersect(&self.db.get_effective_scopes(&api_key)?)
+            .to_strings();
+
+        self.issue_token_pair_in_family(
+            &api_key,
+            scopes,
+            stored.audience.as_deref(),
+            stored.access_token_ttl_seconds.and_then(Duration::try_seconds),
+            &stored.family_id,
+        )
+    }
+
+    fn issue_token_pair_in_family(
+        &self,
+        api_key: &ApiKey,
+        scopes: Vec<String>,
+        audience: Option<&str>,
+        requested_ttl: Option<Duration>,
+        family_id: &str,
+    ) -> Result<TokenPair, ApiError> {
+        let now = Utc::now();
+        // Re-clamped on every refresh in case the key's maximum was lowered
+        let ttl = self.resolve_access_token_ttl(api_key, requested_ttl)?;
+        let (access_token, access_expires_at) = self.issue_access_token(
+            self.db.get_principal(api_key.user_id)?,
+            api_key,
+            scopes.clone(),
+            audience,
+            Some(family_id),
+            ttl,
+        )?;
+
+        // Opaque refresh token: 256 bits of randomness, stored hashed
+        let mut random_bytes = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill(&mut *random_bytes);
+        let refresh_token = SecretString::new(format!(
+            "rt_{}",
+            base32::encode(base32::Alphabet::RFC4648 { padding: false }, &*random_bytes)
+        ));
+        let refresh_expires_at = now + self.refresh_token_ttl;
+
+        self.db.create_refresh_token(
+            api_key.id,
+            family_id,
+            &self.hashing.hash(refresh_token.expose_secret()),
+            self.hashing.algorithm().id(),
+            &scopes,
+            audience,
+            requested_ttl.map(|ttl| ttl.num_seconds()),
+            refresh_expires_at,
+        )?;
+
+        Ok(TokenPair {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: (access_expires_at - now).num_seconds(),
+            refresh_token,
+            refresh_token_expires_in: (refresh_expires_at - now).num_seconds(),
+            scopes,
+            audience: audience.map(str::to_string),
+        })
+    }
+
+    // Validate JWT access token. Tokens without an audience are accepted, as are
+    // tokens for any of the configured audiences.
+    pub fn validate_access_token(&self, token: &str) -> Result<Claims, ApiError> {
+        let claims = self.validate_claims(token)?;
+        if let Some(audience) = &claims.aud {
+            if !self.audiences.contains(audience) {
+                return Err(ApiError::InvalidAudience);
+            }
+        }
+        Ok(claims)
+    }
+
+    // Validate JWT access token on behalf of one audience; tokens minted for other
+    // audiences, or for none, are rejected
+    pub fn validate_access_token_for_audience(
+        &self,
+        token: &str,
+        audience: &str,
+    ) -> Result<Claims, ApiError> {
+        let claims = self.validate_claims(token)?;
+        if claims.aud.as_deref() != Some(audience) {
+            return Err(ApiError::InvalidAudience);
+        }
+        Ok(claims)
+    }
+
+    // Signature, issuer, exp/nbf (with leeway) and revocation checks
+    fn validate_claims(&self, token: &str) -> Result<Claims, ApiError> {
+        let token_data = self.decode_claims(token, true)?;
+
+        // Check the revocation list (covers revocation of the issuing API key)
+        if self.revocations.is_revoked(&token_data.claims.jti)? {
+            return Err(ApiError::TokenRevoked);
+        }
+
+        Ok(token_data.claims)
+    }
+
+    // Revoke an access token. Expired tokens are accepted so they can be revoked
+    // pre-emptively; the signature must still be valid.
+    pub fn revoke_access_token(&self, token: &str) -> Result<Claims, ApiError> {
+        let token_data = self.decode_claims(token, false)?;
+
+        self.db.revoke_access_token(&token_data.claims.jti)?;
+        self.revocations.insert(&token_data.claims.jti);
 
         Ok(token_data.claims)
     }
+
+    // Verify the signature with the keyring key named by the token's kid. Tokens
+    // without a kid (issued before kids were added) are tried against every live key
+    // of the same algorithm. The issuer must match; exp and nbf are only checked
+    // when `validate_times` is set.
+    fn decode_claims(
+        &self,
+        token: &str,
+        validate_times: bool,
+    ) -> Result<jsonwebtoken::TokenData<Claims>, ApiError> {
+        let header = decode_header(token)?;
+        let candidates = match header.kid.as_deref() {
+            Some(kid) => self
+                .keyring
+                .verification_key(kid)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            None => self.keyring.verification_keys(),
+        };
+
+        let mut result = Err(ApiError::InvalidToken);
+        for key in candidates
+            .iter()
+            .filter(|key| key.algorithm() == header.alg)
+        {
+            let mut validation = Validation::new(key.algorithm());
+            validation.set_issuer(&[&self.issuer]);
+            validation.set_required_spec_claims(&["exp", "iss"]);
+            validation.validate_exp = validate_times;
+            validation.validate_nbf = validate_times;
+            validation.validate_aud = false; // checked by the caller
+            validation.leeway = self.leeway;
+
+            result =
+                decode::<Claims>(token, key.decoding_key(), &validation).map_err(ApiError::from);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
 }
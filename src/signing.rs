@@ -0,0 +1,396 @@
+use crate::errors::ApiError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::signature::{self, KeyPair};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+// Key used to sign and verify access tokens.
+//
+// HS256 keys are shared secrets and are never published. RS256, ES256 and EdDSA keys
+// are loaded from a private key; the matching public key is exposed as a JWK so
+// other services can verify tokens without the secret.
+#[derive(Clone)]
+pub struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    jwk: Option<Jwk>,
+}
+
+impl SigningKey {
+    // Shared-secret HS256 key
+    pub fn hmac(kid: String, secret: &[u8]) -> Self {
+        Self {
+            kid,
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            jwk: None,
+        }
+    }
+
+    // Load a PEM encoded private key (PKCS#1 or PKCS#8 for RSA, PKCS#8 otherwise).
+    // Without an explicit kid the RFC 7638 thumbprint of the public key is used.
+    pub fn from_pem(
+        algorithm: Algorithm,
+        pem: &[u8],
+        kid: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let parsed = pem::parse(pem)
+            .map_err(|e| ApiError::InvalidSigningKey(format!("invalid PEM: {}", e)))?;
+        Self::from_der(algorithm, parsed.contents(), kid)
+    }
+
+    // Load a DER encoded private key (PKCS#1 or PKCS#8 for RSA, PKCS#8 otherwise)
+    pub fn from_der(
+        algorithm: Algorithm,
+        der: &[u8],
+        kid: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let (encoding_key, params) = match algorithm {
+            Algorithm::RS256 => {
+                // jsonwebtoken signs with PKCS#1; unwrap PKCS#8 keys via their PEM form
+                let (key_pair, encoding_key) = match signature::RsaKeyPair::from_der(der) {
+                    Ok(key_pair) => (key_pair, EncodingKey::from_rsa_der(der)),
+                    Err(_) => {
+                        let key_pair =
+                            signature::RsaKeyPair::from_pkcs8(der).map_err(invalid_key)?;
+                        let pem = pem::encode(&pem::Pem::new("PRIVATE KEY", der.to_vec()));
+                        let encoding_key = EncodingKey::from_rsa_pem(pem.as_bytes())
+                            .map_err(|e| ApiError::InvalidSigningKey(e.to_string()))?;
+                        (key_pair, encoding_key)
+                    }
+                };
+                let public: signature::RsaPublicKeyComponents<Vec<u8>> = key_pair.public().into();
+                let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(&public.n),
+                    e: URL_SAFE_NO_PAD.encode(&public.e),
+                });
+                (encoding_key, params)
+            }
+            Algorithm::ES256 => {
+                let rng = ring::rand::SystemRandom::new();
+                let key_pair = signature::EcdsaKeyPair::from_pkcs8(
+                    &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                    der,
+                    &rng,
+                )
+                .map_err(invalid_key)?;
+                // Uncompressed point: 0x04 || X || Y
+                let point = key_pair.public_key().as_ref();
+                let params = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                    key_type: EllipticCurveKeyType::EC,
+                    curve: EllipticCurve::P256,
+                    x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                    y: URL_SAFE_NO_PAD.encode(&point[33..65]),
+                });
+                (EncodingKey::from_ec_der(der), params)
+            }
+            Algorithm::EdDSA => {
+                let key_pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+                    .map_err(invalid_key)?;
+                let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+                });
+                (EncodingKey::from_ed_der(der), params)
+            }
+            other => {
+                return Err(ApiError::InvalidSigningKey(format!(
+                    "unsupported algorithm {:?}; expected RS256, ES256 or EdDSA",
+                    other
+                )))
+            }
+        };
+
+        let kid = kid.unwrap_or_else(|| jwk_thumbprint(&params));
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm(algorithm)),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+        let decoding_key =
+            DecodingKey::from_jwk(&jwk).map_err(|e| ApiError::InvalidSigningKey(e.to_string()))?;
+
+        Ok(Self {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+        })
+    }
+
+    // Load a private key file, PEM or DER
+    pub fn from_file(
+        algorithm: Algorithm,
+        path: impl AsRef<Path>,
+        kid: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let bytes = std::fs::read(path.as_ref()).map_err(|e| {
+            ApiError::InvalidSigningKey(format!("cannot read {}: {}", path.as_ref().display(), e))
+        })?;
+        if bytes.starts_with(b"-----BEGIN") {
+            Self::from_pem(algorithm, &bytes, kid)
+        } else {
+            Self::from_der(algorithm, &bytes, kid)
+        }
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    // Public key as a JWK; None for shared-secret keys
+    pub fn jwk(&self) -> Option<&Jwk> {
+        self.jwk.as_ref()
+    }
+
+    pub(crate) fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    pub(crate) fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding_key
+    }
+}
+
+// Never print key material
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+// Set of keys accepted for verification, one of which signs new tokens.
+//
+// Rotating without downtime: introduce the new key (it is published in the JWKS and
+// accepted for verification), promote it once verifiers have picked it up, then retire
+// the old key after the tokens it signed have expired. Clones share the same keyring.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    state: Arc<RwLock<KeyringState>>,
+}
+
+#[derive(Debug)]
+struct KeyringState {
+    keys: Vec<KeyringEntry>,
+    signing_kid: String,
+}
+
+#[derive(Debug)]
+struct KeyringEntry {
+    key: SigningKey,
+    added_at: DateTime<Utc>,
+    retire_at: Option<DateTime<Utc>>,
+}
+
+impl KeyringEntry {
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.retire_at.is_none_or(|retire_at| now < retire_at)
+    }
+}
+
+// Keyring entry as reported by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct SigningKeyInfo {
+    pub kid: String,
+    pub algorithm: String,
+    pub signing: bool,
+    pub added_at: DateTime<Utc>,
+    pub retire_at: Option<DateTime<Utc>>,
+}
+
+impl Keyring {
+    pub fn new(signing_key: SigningKey) -> Self {
+        let signing_kid = signing_key.kid().to_string();
+        Self {
+            state: Arc::new(RwLock::new(KeyringState {
+                keys: vec![KeyringEntry {
+                    key: signing_key,
+                    added_at: Utc::now(),
+                    retire_at: None,
+                }],
+                signing_kid,
+            })),
+        }
+    }
+
+    // Add a verification-only key
+    pub fn introduce(&self, key: SigningKey) -> Result<(), ApiError> {
+        let mut state = self.state.write().unwrap();
+        state.purge(Utc::now());
+        if state.find(key.kid()).is_some() {
+            return Err(ApiError::InvalidRequest(format!(
+                "Signing key {} already exists",
+                key.kid()
+            )));
+        }
+        state.keys.push(KeyringEntry {
+            key,
+            added_at: Utc::now(),
+            retire_at: None,
+        });
+        Ok(())
+    }
+
+    // Sign new tokens with the given key; the previous signing key keeps verifying
+    pub fn promote(&self, kid: &str) -> Result<(), ApiError> {
+        let mut state = self.state.write().unwrap();
+        state.purge(Utc::now());
+        let entry = state.find_mut(kid).ok_or(ApiError::SigningKeyNotFound)?;
+        entry.retire_at = None;
+        state.signing_kid = kid.to_string();
+        Ok(())
+    }
+
+    // Stop accepting a key at the given time. The signing key cannot be retired.
+    pub fn retire(&self, kid: &str, retire_at: DateTime<Utc>) -> Result<(), ApiError> {
+        let mut state = self.state.write().unwrap();
+        state.purge(Utc::now());
+        if state.signing_kid == kid {
+            return Err(ApiError::InvalidRequest(
+                "The signing key cannot be retired; promote another key first".to_string(),
+            ));
+        }
+        state
+            .find_mut(kid)
+            .ok_or(ApiError::SigningKeyNotFound)?
+            .retire_at = Some(retire_at);
+        state.purge(Utc::now());
+        Ok(())
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        let state = self.state.read().unwrap();
+        state
+            .find(&state.signing_kid)
+            .map(|entry| entry.key.clone())
+            .expect("keyring always holds its signing key")
+    }
+
+    // Key accepted for verifying tokens carrying this kid
+    pub fn verification_key(&self, kid: &str) -> Option<SigningKey> {
+        let now = Utc::now();
+        let state = self.state.read().unwrap();
+        state
+            .find(kid)
+            .filter(|entry| entry.is_live(now))
+            .map(|entry| entry.key.clone())
+    }
+
+    // Every key currently accepted for verification, signing key first
+    pub fn verification_keys(&self) -> Vec<SigningKey> {
+        let now = Utc::now();
+        let state = self.state.read().unwrap();
+        let mut keys: Vec<SigningKey> = state
+            .keys
+            .iter()
+            .filter(|entry| entry.is_live(now))
+            .map(|entry| entry.key.clone())
+            .collect();
+        keys.sort_by_key(|key| key.kid() != state.signing_kid);
+        keys
+    }
+
+    // Public keys of every live asymmetric key
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verification_keys()
+                .iter()
+                .filter_map(|key| key.jwk().cloned())
+                .collect(),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<SigningKeyInfo> {
+        let now = Utc::now();
+        let state = self.state.read().unwrap();
+        state
+            .keys
+            .iter()
+            .filter(|entry| entry.is_live(now))
+            .map(|entry| SigningKeyInfo {
+                kid: entry.key.kid().to_string(),
+                algorithm: format!("{:?}", entry.key.algorithm()),
+                signing: entry.key.kid() == state.signing_kid,
+                added_at: entry.added_at,
+                retire_at: entry.retire_at,
+            })
+            .collect()
+    }
+}
+
+impl KeyringState {
+    fn find(&self, kid: &str) -> Option<&KeyringEntry> {
+        self.keys.iter().find(|entry| entry.key.kid() == kid)
+    }
+
+    fn find_mut(&mut self, kid: &str) -> Option<&mut KeyringEntry> {
+        self.keys.iter_mut().find(|entry| entry.key.kid() == kid)
+    }
+
+    // Drop keys whose retirement time has passed
+    fn purge(&mut self, now: DateTime<Utc>) {
+        self.keys.retain(|entry| entry.is_live(now));
+    }
+}
+
+fn invalid_key(err: ring::error::KeyRejected) -> ApiError {
+    ApiError::InvalidSigningKey(err.to_string())
+}
+
+fn key_algorithm(algorithm: Algorithm) -> KeyAlgorithm {
+    match algorithm {
+        Algorithm::RS256 => KeyAlgorithm::RS256,
+        Algorithm::ES256 => KeyAlgorithm::ES256,
+        Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+        _ => KeyAlgorithm::HS256,
+    }
+}
+
+// RFC 7638 thumbprint: SHA-256 over the required members in lexicographic order
+fn jwk_thumbprint(params: &AlgorithmParameters) -> String {
+    let canonical = match params {
+        AlgorithmParameters::RSA(rsa) => {
+            format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+        }
+        AlgorithmParameters::EllipticCurve(ec) => {
+            format!(
+                r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                ec.x, ec.y
+            )
+        }
+        AlgorithmParameters::OctetKeyPair(okp) => {
+            format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, okp.x)
+        }
+        AlgorithmParameters::OctetKey(_) => String::new(),
+    };
+    URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+}
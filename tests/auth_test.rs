@@ -0,0 +1,351 @@
+use std::fs;
+use std::path::Path;
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use secure_api_key::{
+    auth::{AuthMethod, AuthenticatedKey, Authenticator},
+    database::Database,
+    errors::ApiError,
+    security::{ApiKeyService, Claims, TokenService},
+};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+async fn whoami(key: AuthenticatedKey) -> Json<Value> {
+    Json(json!({
+        "user_id": key.user_id,
+        "api_key_id": key.api_key_id,
+        "organization_id": key.organization_id,
+        "via_api_key": key.method == AuthMethod::ApiKey,
+    }))
+}
+
+// テスト用のルーター・APIキー・アクセストークンを準備する
+fn setup(db_name: &str, username: &str) -> (Router, TokenService, String, String) {
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/{}.sqlite", test_db_dir, db_name);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let user_id = db.create_user(username, &format!("{}@example.com", username))
+        .expect("Failed to create user");
+
+    let api_key_service = ApiKeyService::new(
+        db.clone(),
+        "test".to_string(),
+        "dev".to_string(),
+        "test_secret_key".to_string(),
+    );
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+
+    let (api_key, key_hash) = api_key_service.generate_api_key()
+        .expect("Failed to generate API key");
+    let api_key_id = db.create_api_key(
+        user_id,
+        &key_hash,
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        None,
+    ).expect("Failed to create API key in database");
+    let access_token = token_service.generate_access_token(user_id, api_key_id, vec!["read".to_string()])
+        .expect("Failed to generate token");
+
+    let authenticator = Authenticator::new(api_key_service, token_service.clone());
+    let router = Router::new()
+        .route("/reports", get(whoami).route_layer(authenticator.require_scopes(&["read"])))
+        .route("/admin", get(whoami).route_layer(authenticator.require_scopes(&["read", "admin"])))
+        .route("/billing/invoices", get(whoami).route_layer(authenticator.require_scopes(&["billing:read"])))
+        .route("/billing/refunds", get(whoami).route_layer(authenticator.require_scopes(&["billing:write"])))
+        .route("/whoami", get(whoami))
+        .with_state(authenticator);
+
+    (router, token_service, api_key.expose_secret().to_string(), access_token.expose_secret().to_string())
+}
+
+async fn send(router: &Router, uri: &str, header: Option<(&str, String)>) -> (StatusCode, Option<String>, Value) {
+    let mut request = Request::builder().uri(uri);
+    if let Some((name, value)) = header {
+        request = request.header(name, value);
+    }
+    let response = router.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .expect("Request failed");
+
+    let status = response.status();
+    let challenge = response.headers().get(header::WWW_AUTHENTICATE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let body = to_bytes(response.into_body(), usize::MAX).await.expect("Failed to read body");
+    (status, challenge, serde_json::from_slice(&body).expect("Body should be JSON"))
+}
+
+#[tokio::test]
+async fn test_require_scopes_layer() {
+    println!("🧪 Testing scope enforcement layer...");
+
+    let (router, token_service, api_key, access_token) = setup("test_require_scopes", "scopes_user");
+    let bearer = |token: &str| Some(("Authorization", format!("Bearer {}", token)));
+
+    // 認証情報なしは401
+    let (status, challenge, body) = send(&router, "/reports", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(challenge.as_deref(), Some(r#"Bearer error="invalid_token""#));
+    assert_eq!(body["success"], false);
+    assert_eq!(body["error"], "invalid_token");
+
+    // 必要なスコープを持つBearerトークンは通過する
+    let (status, _, body) = send(&router, "/reports", bearer(&access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["via_api_key"], false);
+
+    // APIキーヘッダーでも認証できる
+    let (status, _, body) = send(&router, "/reports", Some(("X-API-Key", api_key.clone()))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["via_api_key"], true);
+
+    // スコープ不足は403で、不足しているスコープを返す
+    let (status, challenge, body) = send(&router, "/admin", bearer(&access_token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(challenge.as_deref(), Some(r#"Bearer error="insufficient_scope", scope="read admin""#));
+    assert_eq!(body["error"], "insufficient_scope");
+    assert_eq!(body["missing_scopes"], json!(["admin"]));
+
+    // 認証できた利用は、トークン経由でも元のキーの利用ログに記録される
+    let logs = token_service.db.list_usage_logs(1, None, 10).expect("Failed to list usage logs");
+    assert_eq!(logs.iter().map(|log| log.endpoint.as_str()).collect::<Vec<_>>(), vec!["/admin", "/reports", "/reports"]);
+    assert!(logs.iter().all(|log| log.success));
+
+    // 不正なAPIキー・失効したトークンは401
+    let (status, _, _) = send(&router, "/reports", Some(("X-API-Key", "test_dev_v1_0_AAAA_AAAA".to_string()))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    token_service.revoke_access_token(&access_token).expect("Failed to revoke token");
+    let (status, _, body) = send(&router, "/reports", bearer(&access_token)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Token has been revoked");
+
+    println!("✅ Scope enforcement layer test passed");
+}
+
+#[tokio::test]
+async fn test_authenticated_key_extractor() {
+    println!("🧪 Testing AuthenticatedKey extractor...");
+
+    let (router, _, api_key, access_token) = setup("test_auth_extractor", "extractor_user");
+
+    // レイヤーなしでもエクストラクターが単独で認証する
+    let (status, _, body) = send(&router, "/whoami", Some(("Authorization", format!("Bearer {}", access_token)))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["api_key_id"].as_i64().unwrap() > 0);
+    assert_eq!(body["organization_id"], 1);
+
+    let (status, _, body) = send(&router, "/whoami", Some(("X-API-Key", api_key))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["via_api_key"], true);
+    assert_eq!(body["organization_id"], 1);
+
+    let (status, _, body) = send(&router, "/whoami", Some(("Authorization", "Basic abc".to_string()))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "invalid_token");
+
+    println!("✅ AuthenticatedKey extractor test passed");
+}
+
+#[tokio::test]
+async fn test_require_scopes_hierarchy() {
+    println!("🧪 Testing wildcard and hierarchical scopes in the layer...");
+
+    let (router, _, _, access_token) = setup("test_require_scopes_hierarchy", "hierarchy_user");
+    let bearer = Some(("Authorization", format!("Bearer {}", access_token)));
+
+    // グローバルな "read" は任意リソースの読み取りを許可するが、書き込みは許可しない
+    let (status, _, _) = send(&router, "/billing/invoices", bearer.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send(&router, "/billing/refunds", bearer).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["missing_scopes"], json!(["billing:write"]));
+
+    println!("✅ Scope hierarchy layer test passed");
+}
+
+#[tokio::test]
+async fn test_tokens_without_organization_claim() {
+    println!("🧪 Testing tokens issued before the organization claim existed...");
+
+    let (router, token_service, _, access_token) = setup("test_legacy_claims", "legacy_claims_user");
+    let issued = token_service.validate_access_token(&access_token).expect("Token should be valid");
+    let api_key_id = issued.api_key_id;
+
+    // 組織・環境のクレームがない旧トークンも、キーの組織で認証される
+    let now = Utc::now();
+    let legacy_claims = Claims {
+        organization_id: None,
+        environment: None,
+        jti: uuid::Uuid::new_v4().to_string(),
+        exp: (now + Duration::hours(1)).timestamp(),
+        ..issued
+    };
+    let header = Header { kid: Some("default".to_string()), ..Header::default() };
+    let legacy_token = encode(&header, &legacy_claims, &EncodingKey::from_secret(b"test_secret_key"))
+        .expect("Failed to encode legacy token");
+    assert!(!serde_json::to_string(&legacy_claims).unwrap().contains("organization_id"));
+
+    let (status, _, body) = send(&router, "/whoami", Some(("Authorization", format!("Bearer {}", legacy_token)))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["organization_id"], 1);
+    assert_eq!(body["api_key_id"], api_key_id);
+
+    println!("✅ Legacy organization claim test passed");
+}
+
+#[tokio::test]
+async fn test_environment_isolation() {
+    println!("🧪 Testing environment-bound routes...");
+
+    let db_path = "tests/test_db/test_environment_isolation.sqlite";
+    fs::create_dir_all("tests/test_db").expect("Failed to create test_db directory");
+    let _ = fs::remove_file(db_path);
+
+    let db = Database::new(db_path).expect("Failed to create test database");
+    let user_id = db.create_user("env_user", "env_user@example.com").expect("Failed to create user");
+    let api_key_service = ApiKeyService::new(db.clone(), "test".to_string(), "prod".to_string(), "test_secret_key".to_string())
+        .with_environments(vec!["test".to_string()]);
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+
+    let mut credentials = Vec::new();
+    for environment in ["prod", "test"] {
+        let (api_key, key_hash) = api_key_service.generate_api_key_for("test", environment)
+            .expect("Failed to generate API key");
+        let key_id = db.create_api_key(user_id, &key_hash, "test", environment, 1, &[String::from("read")], None)
+            .expect("Failed to create API key");
+        let token = token_service.generate_access_token(user_id, key_id, vec!["read".to_string()])
+            .expect("Failed to generate token");
+        credentials.push((api_key, token));
+    }
+
+    let authenticator = Authenticator::new(api_key_service, token_service);
+    let prod = authenticator.clone().with_environments(&["prod"]);
+    let router = Router::new()
+        .route("/prod/reports", get(whoami).route_layer(prod.require_scopes(&["read"])))
+        .route("/reports", get(whoami).route_layer(authenticator.require_scopes(&["read"])))
+        .with_state(authenticator);
+
+    // testキーは、APIキーでもそこから発行したトークンでもprodのルートを通過できない
+    let (prod_credentials, test_credentials) = (&credentials[0], &credentials[1]);
+    let (status, _, _) = send(&router, "/prod/reports", Some(("X-API-Key", prod_credentials.0.expose_secret().to_string()))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = send(&router, "/prod/reports", Some(("Authorization", format!("Bearer {}", prod_credentials.1.expose_secret())))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send(&router, "/prod/reports", Some(("X-API-Key", test_credentials.0.expose_secret().to_string()))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Credential is not valid in this environment");
+    let (status, _, _) = send(&router, "/prod/reports", Some(("Authorization", format!("Bearer {}", test_credentials.1.expose_secret())))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // 環境を指定しないルートはどちらも受け付ける
+    let (status, _, _) = send(&router, "/reports", Some(("X-API-Key", test_credentials.0.expose_secret().to_string()))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    println!("✅ Environment isolation test passed");
+}
+
+#[tokio::test]
+async fn test_serving_environments() {
+    println!("🧪 Testing that a prod deployment rejects test keys on every route...");
+
+    let db_path = "tests/test_db/test_serving_environments.sqlite";
+    fs::create_dir_all("tests/test_db").expect("Failed to create test_db directory");
+    let _ = fs::remove_file(db_path);
+
+    let db = Database::new(db_path).expect("Failed to create test database");
+    let user_id = db.create_user("serving_user", "serving_user@example.com").expect("Failed to create user");
+    db.assign_role(user_id, "admin").expect("Failed to assign role");
+    // testキーも発行できるが、このデプロイはprodのみを提供する
+    let api_key_service = ApiKeyService::new(db.clone(), "test".to_string(), "prod".to_string(), "test_secret_key".to_string())
+        .with_environments(vec!["test".to_string()])
+        .with_serving_environments(vec!["prod".to_string()]);
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string())
+        .with_serving_environments(vec!["prod".to_string()]);
+
+    let mut api_keys = Vec::new();
+    for environment in ["prod", "test"] {
+        let (api_key, key_hash) = api_key_service.generate_api_key_for("test", environment)
+            .expect("Failed to generate API key");
+        db.create_api_key(user_id, &key_hash, "test", environment, 1, &[String::from("read"), String::from("admin")], None)
+            .expect("Failed to create API key");
+        api_keys.push(api_key);
+    }
+    assert!(matches!(
+        api_key_service.check_serving_environment("test"),
+        Err(ApiError::EnvironmentMismatch)
+    ));
+
+    // 他の環境のキーのトークンファミリーはリフレッシュできず、トークンも消費されない
+    let mut pairs = Vec::new();
+    for api_key in &api_keys {
+        let key_data = api_key_service.validate_api_key(api_key.expose_secret()).expect("API key should be valid");
+        pairs.push(token_service.issue_token_pair(&key_data).expect("Failed to issue token pair"));
+    }
+    token_service.refresh_access_token(pairs[0].refresh_token.expose_secret()).expect("Failed to refresh");
+    assert!(matches!(
+        token_service.refresh_access_token(pairs[1].refresh_token.expose_secret()),
+        Err(ApiError::EnvironmentMismatch)
+    ));
+    TokenService::new(db.clone(), "test_secret_key".to_string())
+        .refresh_access_token(pairs[1].refresh_token.expose_secret())
+        .expect("Token should still be unused");
+
+    // ルートごとに環境を指定しなくても、Authenticator::newがデプロイの環境に従う
+    let authenticator = Authenticator::new(api_key_service, token_service);
+    let router = Router::new()
+        .route("/protected", get(whoami).route_layer(authenticator.require_scopes(&["read"])))
+        .route("/admin/scopes", get(whoami).route_layer(authenticator.require_scopes(&["admin"])))
+        .route("/whoami", get(whoami))
+        .with_state(authenticator);
+
+    let (prod_key, test_key) = (api_keys[0].expose_secret().to_string(), api_keys[1].expose_secret().to_string());
+    for path in ["/protected", "/admin/scopes", "/whoami"] {
+        let (status, _, _) = send(&router, path, Some(("X-API-Key", prod_key.clone()))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, body) = send(&router, path, Some(("X-API-Key", test_key.clone()))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["message"], "Credential is not valid in this environment");
+    }
+
+    println!("✅ Serving environments test passed");
+}
+
+#[tokio::test]
+async fn test_rejections_do_not_echo_credentials() {
+    println!("🧪 Testing that rejected credentials are not echoed back...");
+
+    let (router, _, api_key, access_token) = setup("test_rejection_redaction", "redaction_user");
+
+    // 不正なキー・トークンで拒否されても、レスポンスに認証情報は含まれない
+    let tampered_key = format!("{}A", api_key);
+    let tampered_token = format!("{}A", access_token);
+    for header in [
+        ("X-API-Key", tampered_key.clone()),
+        ("Authorization", format!("Bearer {}", tampered_token)),
+        ("Authorization", format!("Token {}", access_token)),
+    ] {
+        let (status, challenge, body) = send(&router, "/reports", Some(header)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let response = format!("{:?} {}", challenge, body);
+        assert!(!response.contains(&api_key[..api_key.len() - 4]));
+        assert!(!response.contains(&access_token[access_token.len() / 2..]));
+    }
+
+    println!("✅ Rejection redaction test passed");
+}
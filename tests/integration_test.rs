@@ -1,8 +1,12 @@
 use std::fs;
 use std::path::Path;
+use std::sync::Arc;
+use chrono::{Duration, Utc};
 use secure_api_key::{
+    clock::{Clock, MockClock},
     database::Database,
-    security::{ApiKeyService, TokenService},
+    errors::ApiError,
+    security::{ApiKeyService, KeyFormat, TokenService},
 };
 
 #[tokio::test]
@@ -124,7 +128,7 @@ async fn test_token_validation() {
         .expect("Failed to generate token");
     
     // トークン検証テスト
-    let validation_result = token_service.validate_access_token(&token);
+    let validation_result = token_service.validate_access_token(token.expose_secret());
     assert!(validation_result.is_ok());
     
     let claims = validation_result.unwrap();
@@ -179,7 +183,7 @@ async fn test_full_workflow() {
     ).expect("Failed to create API key in database");
     
     // 5. APIキー検証
-    let validation_result = api_key_service.validate_api_key(&test_api_key);
+    let validation_result = api_key_service.validate_api_key(test_api_key.expose_secret());
     match validation_result {
         Ok(api_key_data) => {
             println!("API key validation successful: id={}, user_id={}", api_key_data.id, api_key_data.user_id);
@@ -200,7 +204,7 @@ async fn test_full_workflow() {
         .expect("Failed to generate access token");
     
     // 8. アクセストークン検証
-    let token_validation = token_service.validate_access_token(&access_token);
+    let token_validation = token_service.validate_access_token(access_token.expose_secret());
     assert!(token_validation.is_ok());
     
     let token_claims = token_validation.unwrap();
@@ -208,4 +212,320 @@ async fn test_full_workflow() {
     assert_eq!(token_claims.api_key_id, api_key_id);
     
     println!("✅ Full workflow test passed");
-} 
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_api_key_revocation() {
+    println!("🧪 Testing API key revocation...");
+    
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/test_revocation.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+    
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    
+    // ユーザーとAPIキーを作成
+    let user_id = db.create_user("revoke_user", "revoke_user@example.com")
+        .expect("Failed to create user");
+    
+    let api_key_service = ApiKeyService::new(
+        db.clone(),
+        "test".to_string(),
+        "dev".to_string(),
+        "test_secret_key".to_string(),
+    );
+    
+    let (test_api_key, key_hash) = api_key_service.generate_api_key()
+        .expect("Failed to generate API key");
+    
+    let api_key_id = db.create_api_key(
+        user_id,
+        &key_hash,
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        None,
+    ).expect("Failed to create API key in database");
+    
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let access_token = token_service.generate_access_token(user_id, api_key_id, vec!["read".to_string()])
+        .expect("Failed to generate access token");
+    
+    // 失効前はキーもトークンも有効
+    assert!(api_key_service.validate_api_key(test_api_key.expose_secret()).is_ok());
+    assert!(token_service.validate_access_token(access_token.expose_secret()).is_ok());
+    
+    // APIキーを失効させると発行済みトークンも失効する
+    let revoked_tokens = db.revoke_api_key(api_key_id, "leaked in CI logs", Some("security-team"))
+        .expect("Failed to revoke API key");
+    assert_eq!(revoked_tokens, 1);
+    
+    assert!(matches!(
+        api_key_service.validate_api_key(test_api_key.expose_secret()),
+        Err(ApiError::KeyRevoked)
+    ));
+    assert!(matches!(
+        token_service.validate_access_token(access_token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    
+    // 失効情報が保存されている
+    let api_key = db.get_api_key(api_key_id).expect("Failed to get API key");
+    assert!(!api_key.is_active);
+    assert!(api_key.revoked_at.is_some());
+    assert_eq!(api_key.revoked_by.as_deref(), Some("security-team"));
+    assert_eq!(api_key.revocation_reason.as_deref(), Some("leaked in CI logs"));
+    
+    // 二重失効と存在しないキーはエラー
+    assert!(matches!(
+        db.revoke_api_key(api_key_id, "again", None),
+        Err(ApiError::KeyRevoked)
+    ));
+    assert!(matches!(
+        db.revoke_api_key(api_key_id + 100, "missing", None),
+        Err(ApiError::KeyNotFound)
+    ));
+    
+    println!("✅ API key revocation test passed");
+}
+
+#[tokio::test]
+async fn test_api_key_rotation() {
+    println!("🧪 Testing API key rotation...");
+    
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/test_rotation.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+    
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    
+    let user_id = db.create_user("rotate_user", "rotate_user@example.com")
+        .expect("Failed to create user");
+    
+    // v1キーはキーのバージョンを文字列に含む
+    let api_key_service = ApiKeyService::new(
+        db.clone(),
+        "test".to_string(),
+        "dev".to_string(),
+        "test_secret_key".to_string(),
+    )
+    .with_key_format(KeyFormat::V1);
+    
+    let (old_api_key, key_hash) = api_key_service.generate_api_key()
+        .expect("Failed to generate API key");
+    let old_key_id = db.create_api_key(
+        user_id,
+        &key_hash,
+        "test",
+        "dev",
+        1,
+        &[String::from("read"), String::from("write")],
+        None,
+    ).expect("Failed to create API key in database");
+    
+    // ローテーション：後継キーは同じ所有者・スコープでバージョンが上がる
+    let rotated = api_key_service.rotate_api_key(old_key_id, None)
+        .expect("Failed to rotate API key");
+    assert_eq!(rotated.predecessor_id, old_key_id);
+    assert_eq!(rotated.key.predecessor_id, Some(old_key_id));
+    assert_eq!(rotated.key.user_id, user_id);
+    assert_eq!(rotated.key.scopes, vec!["read".to_string(), "write".to_string()]);
+    assert_eq!(rotated.key.version, 2);
+    let rotated_api_key = rotated.api_key.expose_secret();
+    // 上がったバージョンはキーの形式の印にも r<バージョン> として現れる
+    assert!(rotated_api_key.starts_with(&format!("{}_{}_v1r2_", rotated.key.key_prefix, rotated.key.environment)));
+    assert!(rotated.key.masked_key().ends_with(&format!("****{}", &rotated_api_key[rotated_api_key.len() - 4..])));
+    
+    let new_key = api_key_service.validate_api_key(rotated.api_key.expose_secret())
+        .expect("Successor key should be valid");
+    assert!(new_key.deprecation_notice().is_none());
+    
+    // 猶予期間中は旧キーも有効だが非推奨として報告される
+    let old_key = api_key_service.validate_api_key(old_api_key.expose_secret())
+        .expect("Old key should stay valid during the grace period");
+    let notice = old_key.deprecation_notice().expect("Old key should be deprecated");
+    assert!(notice.starts_with("deprecated, rotate by"));
+    
+    // 同じキーを二度ローテーションすることはできない
+    assert!(matches!(
+        api_key_service.rotate_api_key(old_key_id, None),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    
+    // 上限を超える猶予期間はパニックせずに拒否される
+    assert!(matches!(
+        api_key_service.rotate_api_key(rotated.key.id, Some(chrono::Duration::MAX)),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        api_key_service.rotate_api_key(rotated.key.id, Some(chrono::Duration::days(366))),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    
+    // 猶予期間ゼロでローテーションすると旧キーは次回検証時に無効化される
+    let next = api_key_service.rotate_api_key(rotated.key.id, Some(chrono::Duration::zero()))
+        .expect("Failed to rotate API key");
+    assert_eq!(next.key.version, 3);
+    assert!(matches!(
+        api_key_service.validate_api_key(rotated.api_key.expose_secret()),
+        Err(ApiError::KeyInactive)
+    ));
+    assert!(!db.get_api_key(rotated.key.id).expect("Failed to get API key").is_active);
+    
+    println!("✅ API key rotation test passed");
+}
+
+#[tokio::test]
+async fn test_api_key_expiration() {
+    println!("🧪 Testing API key expiration...");
+    
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/test_expiration.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+    
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    
+    let user_id = db.create_user("expiry_user", "expiry_user@example.com")
+        .expect("Failed to create user");
+    
+    // 時刻を制御できるクロックを使用
+    let clock = MockClock::new(Utc::now());
+    let api_key_service = ApiKeyService::new(
+        db.clone(),
+        "test".to_string(),
+        "dev".to_string(),
+        "test_secret_key".to_string(),
+    )
+    .with_clock(Arc::new(clock.clone()))
+    .with_max_key_lifetime(Duration::days(30));
+    
+    // 過去の有効期限と最大有効期間を超える有効期限は拒否される
+    assert!(matches!(
+        api_key_service.resolve_expiry(Some(clock.now() - Duration::minutes(1))),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        api_key_service.resolve_expiry(Some(clock.now() + Duration::days(31))),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    
+    // 有効期限を指定しない場合は最大有効期間が適用される
+    let default_expiry = api_key_service.resolve_expiry(None)
+        .expect("Failed to resolve expiry")
+        .expect("Max lifetime should apply");
+    assert_eq!(default_expiry, clock.now() + Duration::days(30));
+    
+    // 1時間後に期限切れになるキーを作成
+    let expires_at = api_key_service.resolve_expiry(Some(clock.now() + Duration::hours(1)))
+        .expect("Failed to resolve expiry");
+    let (test_api_key, key_hash) = api_key_service.generate_api_key()
+        .expect("Failed to generate API key");
+    let api_key_id = db.create_api_key(
+        user_id,
+        &key_hash,
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        expires_at,
+    ).expect("Failed to create API key in database");
+    
+    // 有効期限が正しく保存・復元される
+    let stored = db.get_api_key(api_key_id).expect("Failed to get API key");
+    assert_eq!(
+        stored.expires_at.map(|dt| dt.timestamp()),
+        expires_at.map(|dt| dt.timestamp())
+    );
+    
+    // 期限前は有効
+    clock.advance(Duration::minutes(59));
+    assert!(api_key_service.validate_api_key(test_api_key.expose_secret()).is_ok());
+    
+    // 期限を過ぎると KeyExpired
+    clock.advance(Duration::minutes(1));
+    assert!(matches!(
+        api_key_service.validate_api_key(test_api_key.expose_secret()),
+        Err(ApiError::KeyExpired)
+    ));
+    
+    println!("✅ API key expiration test passed");
+}
+
+#[tokio::test]
+async fn test_access_token_revocation() {
+    println!("🧪 Testing access token revocation...");
+    
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/test_token_revocation.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+    
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    
+    let user_id = db.create_user("token_revoke_user", "token_revoke_user@example.com")
+        .expect("Failed to create user");
+    let api_key_id = db.create_api_key(
+        user_id,
+        "test_token_revocation_hash",
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        None,
+    ).expect("Failed to create API key");
+    
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let token = token_service.generate_access_token(user_id, api_key_id, vec!["read".to_string()])
+        .expect("Failed to generate token");
+    let other_token = token_service.generate_access_token(user_id, api_key_id, vec!["read".to_string()])
+        .expect("Failed to generate token");
+    
+    // jti がトークンごとに一意で access_tokens の行と紐づく
+    let claims = token_service.validate_access_token(token.expose_secret()).expect("Token should be valid");
+    let other_claims = token_service.validate_access_token(other_token.expose_secret()).expect("Token should be valid");
+    assert_ne!(claims.jti, other_claims.jti);
+    
+    // 別プロセスを想定：別の接続と即時リフレッシュのキャッシュを持つサービス
+    let other_db = Database::new(&db_path).expect("Failed to open test database");
+    let other_service = TokenService::new(other_db, "test_secret_key".to_string())
+        .with_revocation_refresh_interval(std::time::Duration::ZERO);
+    assert!(other_service.validate_access_token(token.expose_secret()).is_ok());
+    
+    // トークンを失効させる
+    let revoked = token_service.revoke_access_token(token.expose_secret()).expect("Failed to revoke token");
+    assert_eq!(revoked.jti, claims.jti);
+    
+    // 失効したトークンのみ拒否される
+    assert!(matches!(
+        token_service.validate_access_token(token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    assert!(token_service.validate_access_token(other_token.expose_secret()).is_ok());
+    
+    // 他のインスタンスもデータベースから失効リストを読み込む
+    assert!(matches!(
+        other_service.validate_access_token(token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    
+    // 改ざんされたトークンは失効できない
+    assert!(token_service.revoke_access_token(&format!("{}x", other_token.expose_secret())).is_err());
+    
+    println!("✅ Access token revocation test passed");
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::Instant;
+use secure_api_key::{
+    database::Database,
+    errors::ApiError,
+    security::{constant_time_eq, ApiKeyService, KeyFormat},
+};
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+// 1サンプルあたりの検証回数とサンプル数
+const BATCH: usize = 50;
+const ROUNDS: usize = 301;
+
+// テスト用のAPIキーサービスと、指定した形式の有効なキーを準備する
+fn setup(db_name: &str, key_format: KeyFormat) -> (ApiKeyService, String) {
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/{}.sqlite", test_db_dir, db_name);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let api_key_service = ApiKeyService::new(
+        db,
+        "test".to_string(),
+        "dev".to_string(),
+        "test_secret_key".to_string(),
+    )
+    .with_key_format(key_format);
+    let (api_key, _) = api_key_service.generate_api_key().expect("Failed to generate API key");
+    (api_key_service, api_key.expose_secret().to_string())
+}
+
+// `index`番目の文字を別の文字に置き換える。v1キーはデコード後のビットが必ず変わる
+// base32文字、v2キーは別のbase62文字にする
+fn corrupt(key: &str, index: usize, key_format: KeyFormat) -> String {
+    let mut bytes = key.as_bytes().to_vec();
+    bytes[index] = match key_format {
+        KeyFormat::V1 => {
+            let value = BASE32_ALPHABET.iter().position(|&c| c == bytes[index]).expect("Not a base32 character");
+            BASE32_ALPHABET[value ^ 0b11000]
+        }
+        KeyFormat::V2 => if bytes[index] == b'A' { b'B' } else { b'A' },
+    };
+    String::from_utf8(bytes).unwrap()
+}
+
+// 各キーの検証時間の中央値(ナノ秒)。計測順の偏りを避けるため交互に計測する
+fn median_validation_nanos(api_key_service: &ApiKeyService, keys: &[String]) -> Vec<u128> {
+    let mut samples = vec![Vec::with_capacity(ROUNDS); keys.len()];
+    for _ in 0..ROUNDS {
+        for (key, samples) in keys.iter().zip(samples.iter_mut()) {
+            let start = Instant::now();
+            for _ in 0..BATCH {
+                let _ = black_box(api_key_service.validate_api_key_format(black_box(key)));
+            }
+            samples.push(start.elapsed().as_nanos());
+        }
+    }
+
+    samples
+        .into_iter()
+        .map(|mut samples| {
+            samples.sort_unstable();
+            samples[ROUNDS / 2]
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_constant_time_eq() {
+    println!("🧪 Testing constant-time comparison...");
+
+    assert!(constant_time_eq(b"checksum", b"checksum"));
+    assert!(!constant_time_eq(b"checksum", b"Checksum"));
+    assert!(!constant_time_eq(b"checksum", b"checksuM"));
+    assert!(!constant_time_eq(b"checksum", b"checksum!"));
+    assert!(constant_time_eq(b"", b""));
+
+    println!("✅ Constant-time comparison test passed");
+}
+
+// チェックサムの先頭・末尾、ランダム部分をそれぞれ改ざんしたキー
+fn corrupted_keys(api_key: &str, key_format: KeyFormat) -> Vec<String> {
+    let checksum_start = api_key.rfind('_').unwrap() + 1;
+    let random_start = api_key[..checksum_start - 1].rfind('_').unwrap() + 1;
+    vec![
+        corrupt(api_key, checksum_start, key_format),
+        corrupt(api_key, api_key.len() - 1, key_format),
+        corrupt(api_key, random_start, key_format),
+    ]
+}
+
+#[tokio::test]
+async fn test_checksum_mismatch_takes_one_path() {
+    println!("🧪 Testing that every checksum mismatch reaches the constant-time comparison...");
+
+    for (db_name, key_format) in [
+        ("test_checksum_path_v1", KeyFormat::V1),
+        ("test_checksum_path_v2", KeyFormat::V2),
+    ] {
+        let (api_key_service, api_key) = setup(db_name, key_format);
+        api_key_service.validate_api_key_format(&api_key).expect("Generated key should be valid");
+
+        // どこを改ざんしても形式の検査を通り、同じ比較で同じエラーになる
+        for key in corrupted_keys(&api_key, key_format) {
+            assert_eq!(key.len(), api_key.len());
+            assert!(matches!(api_key_service.validate_api_key_format(&key), Err(ApiError::InvalidChecksum)));
+        }
+    }
+
+    println!("✅ Checksum mismatch path test passed");
+}
+
+// 計測はマシンの負荷に左右されるため通常のテストでは実行しない:
+// cargo test --release --test timing_test -- --ignored
+#[tokio::test]
+#[ignore = "timing benchmark"]
+async fn test_checksum_validation_timing() {
+    println!("🧪 Testing that key validation time does not depend on where a key is wrong...");
+
+    for (db_name, key_format) in [
+        ("test_validation_timing_v1", KeyFormat::V1),
+        ("test_validation_timing_v2", KeyFormat::V2),
+    ] {
+        let (api_key_service, api_key) = setup(db_name, key_format);
+        api_key_service.validate_api_key_format(&api_key).expect("Generated key should be valid");
+        let keys = corrupted_keys(&api_key, key_format);
+
+        // どこを間違えても中央値がほぼ同じであること(環境ノイズを考慮して閾値は緩め)
+        let medians = median_validation_nanos(&api_key_service, &keys);
+        let fastest = *medians.iter().min().unwrap() as f64;
+        let slowest = *medians.iter().max().unwrap() as f64;
+        println!("   {:?} median ns per {} validations: {:?}", key_format, BATCH, medians);
+        assert!(
+            slowest / fastest < 1.25,
+            "{:?} validation time depends on the corrupted position: {:?}",
+            key_format,
+            medians
+        );
+    }
+
+    println!("✅ Checksum validation timing test passed");
+}
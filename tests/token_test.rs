@@ -0,0 +1,848 @@
+use std::fs;
+use std::path::Path;
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use secure_api_key::{
+    database::Database,
+    errors::ApiError,
+    hashing::{migrate_credential_hashes, CredentialHashing, HashAlgorithm, HashScheme},
+    models::{Principal, RefreshTokenRequest},
+    secret::SecretString,
+    security::{check_v2_key, ApiKeyService, Claims, KeyFormat, TokenOptions, TokenService},
+    signing::SigningKey,
+};
+use sha2::{Digest, Sha256};
+
+// テスト用のデータベース・ユーザー・APIキーを準備する
+fn setup(db_name: &str, username: &str) -> (Database, ApiKeyService, String) {
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/{}.sqlite", test_db_dir, db_name);
+    let _ = fs::remove_file(&db_path);
+    
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let user_id = db.create_user(username, &format!("{}@example.com", username))
+        .expect("Failed to create user");
+    
+    let api_key_service = ApiKeyService::new(
+        db.clone(),
+        "test".to_string(),
+        "dev".to_string(),
+        "test_secret_key".to_string(),
+    );
+    let (api_key, key_hash) = api_key_service.generate_api_key()
+        .expect("Failed to generate API key");
+    db.create_api_key(
+        user_id,
+        &key_hash,
+        "test",
+        "dev",
+        1,
+        &[String::from("read"), String::from("write")],
+        None,
+    ).expect("Failed to create API key in database");
+    
+    (db, api_key_service, api_key.expose_secret().to_string())
+}
+
+#[tokio::test]
+async fn test_token_exchange_and_refresh() {
+    println!("🧪 Testing token exchange and refresh...");
+    
+    let (db, api_key_service, api_key) = setup("test_token_exchange", "exchange_user");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    
+    // APIキーをアクセストークンとリフレッシュトークンに交換
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    let first = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert_eq!(first.token_type, "Bearer");
+    assert_eq!(first.expires_in, 3600);
+    assert!(first.refresh_token.expose_secret().starts_with("rt_"));
+    assert_eq!(first.scopes, vec!["read".to_string(), "write".to_string()]);
+    assert!(token_service.validate_access_token(first.access_token.expose_secret()).is_ok());
+    
+    // リフレッシュすると新しいペアが発行され、スコープが引き継がれる
+    let second = token_service.refresh_access_token(first.refresh_token.expose_secret())
+        .expect("Failed to refresh token");
+    assert_ne!(second.refresh_token, first.refresh_token);
+    assert_eq!(second.scopes, first.scopes);
+    assert!(token_service.validate_access_token(second.access_token.expose_secret()).is_ok());
+    
+    // 使用済みリフレッシュトークンの再利用はファミリー全体を失効させる
+    assert!(matches!(
+        token_service.refresh_access_token(first.refresh_token.expose_secret()),
+        Err(ApiError::TokenReuseDetected)
+    ));
+    assert!(matches!(
+        token_service.validate_access_token(first.access_token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    assert!(matches!(
+        token_service.validate_access_token(second.access_token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    assert!(matches!(
+        token_service.refresh_access_token(second.refresh_token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    
+    // 別の交換で発行されたファミリーには影響しない
+    let other = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert!(token_service.refresh_access_token(other.refresh_token.expose_secret()).is_ok());
+    
+    // 不明なリフレッシュトークンは拒否される
+    assert!(matches!(
+        token_service.refresh_access_token("rt_UNKNOWN"),
+        Err(ApiError::InvalidToken)
+    ));
+    
+    println!("✅ Token exchange and refresh test passed");
+}
+
+#[tokio::test]
+async fn test_refresh_token_expiry_and_key_revocation() {
+    println!("🧪 Testing refresh token expiry and key revocation...");
+    
+    let (db, api_key_service, api_key) = setup("test_refresh_expiry", "refresh_expiry_user");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    
+    // 有効期限切れのリフレッシュトークンは使えない
+    let short_lived = TokenService::new(db.clone(), "test_secret_key".to_string())
+        .with_refresh_token_ttl(Duration::zero());
+    let pair = short_lived.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert!(matches!(
+        short_lived.refresh_access_token(pair.refresh_token.expose_secret()),
+        Err(ApiError::TokenExpired)
+    ));
+    
+    // APIキーを失効させるとリフレッシュトークンも使えなくなる
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    db.revoke_api_key(key_data.id, "compromised", None).expect("Failed to revoke API key");
+    assert!(matches!(
+        token_service.refresh_access_token(pair.refresh_token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    
+    println!("✅ Refresh token expiry and key revocation test passed");
+}
+
+#[tokio::test]
+async fn test_asymmetric_signing_and_jwks() {
+    println!("🧪 Testing asymmetric signing and JWKS...");
+    
+    let (db, api_key_service, api_key) = setup("test_asymmetric_signing", "asymmetric_user");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    
+    // 共有鍵(HS256)は公開されない
+    let hmac_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    assert!(hmac_service.jwks().keys.is_empty());
+    
+    let fixtures = [
+        (Algorithm::RS256, "tests/keys/rs256_private.pem"),
+        (Algorithm::ES256, "tests/keys/es256_private.pem"),
+        (Algorithm::EdDSA, "tests/keys/eddsa_private.pem"),
+    ];
+    for (algorithm, path) in fixtures {
+        let signing_key = SigningKey::from_file(algorithm, path, None)
+            .expect("Failed to load signing key");
+        let token_service = TokenService::new(db.clone(), "test_secret_key".to_string())
+            .with_signing_key(signing_key.clone());
+        
+        let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+        let header = decode_header(pair.access_token.expose_secret()).expect("Failed to decode header");
+        assert_eq!(header.alg, algorithm);
+        assert_eq!(header.kid.as_deref(), Some(signing_key.kid()));
+        assert!(token_service.validate_access_token(pair.access_token.expose_secret()).is_ok());
+        
+        // JWKSの公開鍵だけで検証できる
+        let jwks = token_service.jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        let jwk = jwks.find(signing_key.kid()).expect("kid should be published");
+        let decoding_key = DecodingKey::from_jwk(jwk).expect("Failed to build decoding key");
+        let claims = decode::<Claims>(pair.access_token.expose_secret(), &decoding_key, &Validation::new(algorithm))
+            .expect("Token should verify with the published key")
+            .claims;
+        assert_eq!(claims.api_key_id, key_data.id);
+        
+        // 共有鍵のサービスでは検証できない
+        assert!(matches!(
+            hmac_service.validate_access_token(pair.access_token.expose_secret()),
+            Err(ApiError::InvalidToken)
+        ));
+    }
+    
+    // DER(PKCS#1)でも同じ鍵として読み込める
+    let from_pem = SigningKey::from_file(Algorithm::RS256, "tests/keys/rs256_private.pem", None)
+        .expect("Failed to load PEM key");
+    let from_der = SigningKey::from_file(Algorithm::RS256, "tests/keys/rs256_private_pkcs1.der", None)
+        .expect("Failed to load DER key");
+    assert_eq!(from_pem.kid(), from_der.kid());
+    
+    // 明示したkidが使われる
+    let named = SigningKey::from_file(Algorithm::ES256, "tests/keys/es256_private.pem", Some("es-2024".to_string()))
+        .expect("Failed to load signing key");
+    assert_eq!(named.kid(), "es-2024");
+    
+    // アルゴリズムと鍵の種類が合わなければ拒否される
+    assert!(matches!(
+        SigningKey::from_file(Algorithm::ES256, "tests/keys/rs256_private.pem", None),
+        Err(ApiError::InvalidSigningKey(_))
+    ));
+    
+    println!("✅ Asymmetric signing and JWKS test passed");
+}
+
+#[tokio::test]
+async fn test_signing_key_rotation() {
+    println!("🧪 Testing signing key rotation...");
+    
+    let (db, api_key_service, api_key) = setup("test_signing_key_rotation", "key_rotation_user");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string())
+        .with_signing_key(SigningKey::hmac("hs-1".to_string(), b"test_secret_key"));
+    let old_token = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair").access_token;
+    
+    // kidのない旧形式のトークンも、同じアルゴリズムの鍵で検証される
+    let now = Utc::now();
+    let legacy_claims = Claims {
+        iss: "secure-api-key".to_string(),
+        sub: key_data.user_id.to_string(),
+        aud: None,
+        api_key_id: key_data.id,
+        organization_id: None,
+        environment: None,
+        scopes: vec!["read".to_string()],
+        exp: (now + Duration::hours(1)).timestamp(),
+        nbf: now.timestamp(),
+        iat: now.timestamp(),
+        jti: "legacy-jti".to_string(),
+    };
+    let legacy_token = encode(&Header::default(), &legacy_claims, &EncodingKey::from_secret(b"test_secret_key"))
+        .expect("Failed to encode legacy token");
+    assert!(token_service.validate_access_token(&legacy_token).is_ok());
+    
+    // 新しい鍵を追加しても、昇格するまでは署名に使われない
+    let es_key = SigningKey::from_file(Algorithm::ES256, "tests/keys/es256_private.pem", Some("es-1".to_string()))
+        .expect("Failed to load signing key");
+    token_service.keyring.introduce(es_key.clone()).expect("Failed to introduce key");
+    assert!(matches!(
+        token_service.keyring.introduce(es_key),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    assert_eq!(token_service.jwks().keys.len(), 1);
+    let token = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair").access_token;
+    assert_eq!(decode_header(token.expose_secret()).unwrap().kid.as_deref(), Some("hs-1"));
+    
+    // 昇格後は新しい鍵で署名され、古い鍵で署名済みのトークンも有効なまま
+    let other_handle = token_service.clone();
+    other_handle.keyring.promote("es-1").expect("Failed to promote key");
+    let new_token = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair").access_token;
+    assert_eq!(decode_header(new_token.expose_secret()).unwrap().kid.as_deref(), Some("es-1"));
+    assert!(token_service.validate_access_token(old_token.expose_secret()).is_ok());
+    assert!(token_service.validate_access_token(new_token.expose_secret()).is_ok());
+    
+    // 署名鍵は引退させられない・存在しない鍵はエラー
+    assert!(matches!(
+        token_service.retire_signing_key("es-1", None),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        token_service.keyring.promote("unknown"),
+        Err(ApiError::SigningKeyNotFound)
+    ));
+    
+    // 引退予定の鍵は予定時刻までは検証に使われる
+    let retire_at = token_service.retire_signing_key("hs-1", None).expect("Failed to schedule retirement");
+    assert!(retire_at > Utc::now());
+    assert!(token_service.validate_access_token(old_token.expose_secret()).is_ok());
+    let keys = token_service.keyring.keys();
+    assert_eq!(keys.len(), 2);
+    assert!(keys.iter().any(|key| key.kid == "es-1" && key.signing));
+    assert!(keys.iter().any(|key| key.kid == "hs-1" && key.retire_at == Some(retire_at)));
+    
+    // 引退後は古い鍵のトークンが拒否される
+    token_service.retire_signing_key("hs-1", Some(Utc::now())).expect("Failed to retire key");
+    assert!(matches!(
+        token_service.validate_access_token(old_token.expose_secret()),
+        Err(ApiError::InvalidToken)
+    ));
+    assert!(matches!(
+        token_service.validate_access_token(&legacy_token),
+        Err(ApiError::InvalidToken)
+    ));
+    assert!(token_service.validate_access_token(new_token.expose_secret()).is_ok());
+    assert_eq!(token_service.keyring.keys().len(), 1);
+    
+    println!("✅ Signing key rotation test passed");
+}
+
+#[tokio::test]
+async fn test_token_audience_and_standard_claims() {
+    println!("🧪 Testing audience and standard claims...");
+    
+    let (db, api_key_service, api_key) = setup("test_token_audience", "audience_user");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string())
+        .with_issuer("test-issuer".to_string())
+        .with_audiences(vec!["billing".to_string(), "reports".to_string()])
+        .with_leeway(0);
+    
+    // 指定したオーディエンス向けのトークンを発行
+    let billing = TokenOptions { audience: Some("billing".to_string()), ..TokenOptions::default() };
+    let pair = token_service.issue_token_pair_with(&key_data, &billing).expect("Failed to issue token pair");
+    assert_eq!(pair.audience.as_deref(), Some("billing"));
+    let claims = token_service.validate_access_token_for_audience(pair.access_token.expose_secret(), "billing")
+        .expect("Token should be valid for billing");
+    assert_eq!(claims.iss, "test-issuer");
+    assert_eq!(claims.aud.as_deref(), Some("billing"));
+    assert_eq!(claims.nbf, claims.iat);
+    assert!(!claims.jti.is_empty());
+    assert!(token_service.validate_access_token(pair.access_token.expose_secret()).is_ok());
+    
+    // 他のオーディエンスでは拒否される
+    assert!(matches!(
+        token_service.validate_access_token_for_audience(pair.access_token.expose_secret(), "reports"),
+        Err(ApiError::InvalidAudience)
+    ));
+    let reports_only = TokenService::new(db.clone(), "test_secret_key".to_string())
+        .with_issuer("test-issuer".to_string())
+        .with_audiences(vec!["reports".to_string()]);
+    assert!(matches!(
+        reports_only.validate_access_token(pair.access_token.expose_secret()),
+        Err(ApiError::InvalidAudience)
+    ));
+    
+    // 未登録のオーディエンスには発行できない
+    let unknown = TokenOptions { audience: Some("unknown".to_string()), ..TokenOptions::default() };
+    assert!(matches!(
+        token_service.issue_token_pair_with(&key_data, &unknown),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    
+    // オーディエンスなしのトークンは特定オーディエンスの検証を通らない
+    let unrestricted = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert!(token_service.validate_access_token(unrestricted.access_token.expose_secret()).is_ok());
+    assert!(matches!(
+        token_service.validate_access_token_for_audience(unrestricted.access_token.expose_secret(), "billing"),
+        Err(ApiError::InvalidAudience)
+    ));
+    
+    // リフレッシュ後もオーディエンスは維持される
+    let refreshed = token_service.refresh_access_token(pair.refresh_token.expose_secret()).expect("Failed to refresh");
+    assert_eq!(refreshed.audience.as_deref(), Some("billing"));
+    assert!(token_service.validate_access_token_for_audience(refreshed.access_token.expose_secret(), "billing").is_ok());
+    
+    // 発行者が異なるトークンは拒否される
+    let other_issuer = TokenService::new(db.clone(), "test_secret_key".to_string());
+    assert!(matches!(
+        other_issuer.validate_access_token(pair.access_token.expose_secret()),
+        Err(ApiError::InvalidToken)
+    ));
+    
+    // nbf・expは許容誤差(leeway)を考慮して検証される
+    let now = Utc::now();
+    let sign = |nbf: i64, exp: i64| {
+        let claims = Claims {
+            iss: "test-issuer".to_string(),
+            sub: key_data.user_id.to_string(),
+            aud: None,
+            api_key_id: key_data.id,
+            organization_id: Some(key_data.organization_id),
+            environment: None,
+            scopes: vec!["read".to_string()],
+            exp,
+            nbf,
+            iat: now.timestamp(),
+            jti: uuid::Uuid::new_v4().to_string(),
+        };
+        let header = Header { kid: Some("default".to_string()), ..Header::default() };
+        encode(&header, &claims, &EncodingKey::from_secret(b"test_secret_key")).expect("Failed to encode token")
+    };
+    let future = sign((now + Duration::seconds(30)).timestamp(), (now + Duration::hours(1)).timestamp());
+    let expired = sign((now - Duration::hours(1)).timestamp(), (now - Duration::seconds(30)).timestamp());
+    assert!(matches!(token_service.validate_access_token(&future), Err(ApiError::TokenNotYetValid)));
+    assert!(matches!(token_service.validate_access_token(&expired), Err(ApiError::TokenExpired)));
+    
+    let tolerant = token_service.clone().with_leeway(60);
+    assert!(tolerant.validate_access_token(&future).is_ok());
+    assert!(tolerant.validate_access_token(&expired).is_ok());
+    
+    println!("✅ Audience and standard claims test passed");
+}
+
+#[tokio::test]
+async fn test_access_token_lifetime() {
+    println!("🧪 Testing access token lifetime limits...");
+    
+    let (db, api_key_service, api_key) = setup("test_token_lifetime", "lifetime_user");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string())
+        .with_access_token_ttl(Duration::minutes(10))
+        .with_max_access_token_ttl(Duration::hours(1));
+    let with_ttl = |ttl: Duration| TokenOptions { ttl: Some(ttl), ..TokenOptions::default() };
+    
+    // 指定なしはサーバーのデフォルト、指定があればサーバー上限までで切り詰め
+    let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert_eq!(pair.expires_in, 600);
+    let short = token_service.issue_token_pair_with(&key_data, &with_ttl(Duration::minutes(5)))
+        .expect("Failed to issue token pair");
+    assert_eq!(short.expires_in, 300);
+    let long = token_service.issue_token_pair_with(&key_data, &with_ttl(Duration::hours(8)))
+        .expect("Failed to issue token pair");
+    assert_eq!(long.expires_in, 3600);
+    let claims = token_service.validate_access_token(long.access_token.expose_secret()).expect("Token should be valid");
+    assert_eq!(claims.exp - claims.iat, 3600);
+    assert!(matches!(
+        token_service.issue_token_pair_with(&key_data, &with_ttl(Duration::zero())),
+        Err(ApiError::InvalidRequest(_))
+    ));
+    
+    // 要求したTTLはリフレッシュ後も引き継がれる
+    let refreshed = token_service.refresh_access_token(short.refresh_token.expose_secret()).expect("Failed to refresh");
+    assert_eq!(refreshed.expires_in, 300);
+    
+    // APIキーごとの上限が適用される
+    db.set_api_key_max_token_ttl(key_data.id, Some(120)).expect("Failed to set key maximum");
+    let key_data = db.get_api_key(key_data.id).expect("Failed to get API key");
+    assert_eq!(key_data.max_token_ttl_seconds, Some(120));
+    let capped = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert_eq!(capped.expires_in, 120);
+    let refreshed = token_service.refresh_access_token(refreshed.refresh_token.expose_secret()).expect("Failed to refresh");
+    assert_eq!(refreshed.expires_in, 120);
+    let token = token_service.generate_access_token(key_data.user_id, key_data.id, vec!["read".to_string()])
+        .expect("Failed to generate token");
+    let claims = token_service.validate_access_token(token.expose_secret()).expect("Token should be valid");
+    assert_eq!(claims.exp - claims.iat, 120);
+    
+    // ローテーション後のキーにも上限が引き継がれる
+    let rotated = api_key_service.rotate_api_key(key_data.id, None).expect("Failed to rotate API key");
+    assert_eq!(rotated.key.max_token_ttl_seconds, Some(120));
+    
+    // 範囲外の上限が保存されていても、パニックせずサーバーの設定に従う
+    db.set_api_key_max_token_ttl(key_data.id, Some(i64::MAX)).expect("Failed to set key maximum");
+    let key_data = db.get_api_key(key_data.id).expect("Failed to get API key");
+    assert_eq!(token_service.resolve_access_token_ttl(&key_data, None).unwrap(), Duration::minutes(10));
+    
+    println!("✅ Access token lifetime test passed");
+}
+
+#[tokio::test]
+async fn test_token_downscoping() {
+    println!("🧪 Testing token downscoping...");
+    
+    let (db, api_key_service, api_key) = setup("test_token_downscoping", "downscope_user");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let with_scopes = |scopes: &[&str]| TokenOptions {
+        scopes: Some(scopes.iter().map(|scope| scope.to_string()).collect()),
+        ..TokenOptions::default()
+    };
+    
+    // キーのスコープの一部だけを持つトークンを発行できる
+    let narrow = token_service.issue_token_pair_with(&key_data, &with_scopes(&["read"]))
+        .expect("Failed to issue downscoped token pair");
+    assert_eq!(narrow.scopes, vec!["read".to_string()]);
+    let claims = token_service.validate_access_token(narrow.access_token.expose_secret()).expect("Token should be valid");
+    assert_eq!(claims.scopes, vec!["read".to_string()]);
+    
+    // リフレッシュしても狭いスコープのまま
+    let refreshed = token_service.refresh_access_token(narrow.refresh_token.expose_secret()).expect("Failed to refresh");
+    assert_eq!(refreshed.scopes, vec!["read".to_string()]);
+    
+    // キーが持たないスコープへの昇格は拒否する
+    assert!(matches!(
+        token_service.issue_token_pair_with(&key_data, &with_scopes(&["read", "admin"])),
+        Err(ApiError::InsufficientScope(missing)) if missing == "admin"
+    ));
+    assert!(matches!(
+        token_service.generate_access_token(key_data.user_id, key_data.id, vec!["admin".to_string()]),
+        Err(ApiError::InsufficientScope(_))
+    ));
+    
+    // 階層で含まれるスコープへの絞り込みは許可する
+    let key_id = db.create_api_key(key_data.user_id, "test_downscoping_admin_hash", "test", "dev", 1, &[String::from("admin")], None)
+        .expect("Failed to create API key");
+    let admin_key = db.get_api_key(key_id).expect("Failed to get API key");
+    let pair = token_service.issue_token_pair_with(&admin_key, &with_scopes(&["write"]))
+        .expect("Failed to issue downscoped token pair");
+    assert_eq!(pair.scopes, vec!["write".to_string()]);
+    
+    println!("✅ Token downscoping test passed");
+}
+
+#[tokio::test]
+async fn test_role_constrained_scopes() {
+    println!("🧪 Testing role-based scope constraints...");
+    
+    let (db, api_key_service, api_key) = setup("test_role_scopes", "role_user");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    assert_eq!(key_data.scopes, vec!["read".to_string(), "write".to_string()]);
+    let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    
+    // developer から viewer へ降格すると既存キーのスコープも狭まる
+    db.assign_role(key_data.user_id, "viewer").expect("Failed to assign role");
+    assert!(db.unassign_role(key_data.user_id, "developer").expect("Failed to unassign role"));
+    // 降格前に発行されたアクセストークンは失効する
+    assert!(matches!(
+        token_service.validate_access_token(pair.access_token.expose_secret()),
+        Err(ApiError::TokenRevoked)
+    ));
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    assert_eq!(key_data.scopes, vec!["read".to_string()]);
+    let narrowed = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    assert_eq!(narrowed.scopes, vec!["read".to_string()]);
+    
+    // ロールが許可しないスコープは要求できない
+    let write = TokenOptions { scopes: Some(vec!["write".to_string()]), ..TokenOptions::default() };
+    assert!(matches!(
+        token_service.issue_token_pair_with(&key_data, &write),
+        Err(ApiError::InsufficientScope(missing)) if missing == "write"
+    ));
+    
+    // 降格前に発行されたファミリーもリフレッシュ時に狭まる
+    let refreshed = token_service.refresh_access_token(pair.refresh_token.expose_secret()).expect("Failed to refresh");
+    assert_eq!(refreshed.scopes, vec!["read".to_string()]);
+    
+    // スコープが変わらないロールの削除ではトークンは失効しない
+    db.assign_role(key_data.user_id, "developer").expect("Failed to assign role");
+    let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    db.assign_role(key_data.user_id, "admin").expect("Failed to assign role");
+    assert!(db.unassign_role(key_data.user_id, "developer").expect("Failed to unassign role"));
+    token_service.validate_access_token(pair.access_token.expose_secret()).expect("Token should still be valid");
+    
+    // 昇格すればキー本来のスコープに戻る
+    db.assign_role(key_data.user_id, "admin").expect("Failed to assign role");
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    assert_eq!(key_data.scopes, vec!["read".to_string(), "write".to_string()]);
+    
+    println!("✅ Role-based scope constraint test passed");
+}
+
+#[tokio::test]
+async fn test_service_account_keys() {
+    println!("🧪 Testing service account keys...");
+    
+    let (db, api_key_service, user_api_key) = setup("test_service_accounts", "service_owner");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    
+    // サービスアカウントは人間のユーザーとは別のプリンシパルとしてキーを所有する
+    let account_id = db.create_service_account(1, "ci-pipeline", Some("platform"))
+        .expect("Failed to create service account");
+    assert!(matches!(db.get_user(account_id), Err(ApiError::UserNotFound)));
+    assert_eq!(db.get_principal(account_id).unwrap(), Principal::Service(account_id));
+    
+    let (api_key, key_hash) = api_key_service.generate_api_key().expect("Failed to generate API key");
+    db.create_api_key(account_id, &key_hash, "test", "dev", 1, &[String::from("read")], None)
+        .expect("Failed to create API key");
+    let key_data = api_key_service.validate_api_key(api_key.expose_secret()).expect("API key should be valid");
+    
+    // subクレームでユーザーとサービスアカウントを区別する
+    let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    let claims = token_service.validate_access_token(pair.access_token.expose_secret()).expect("Token should be valid");
+    assert_eq!(claims.sub, format!("service:{}", account_id));
+    assert_eq!(claims.principal().unwrap(), Principal::Service(account_id));
+    
+    let user_key = api_key_service.validate_api_key(&user_api_key).expect("API key should be valid");
+    let user_pair = token_service.issue_token_pair(&user_key).expect("Failed to issue token pair");
+    let user_claims = token_service.validate_access_token(user_pair.access_token.expose_secret()).expect("Token should be valid");
+    assert_eq!(user_claims.sub, user_key.user_id.to_string());
+    assert_eq!(user_claims.principal().unwrap(), Principal::User(user_key.user_id));
+    
+    // 独自のロールを持ち、チーム間で移管してもキーは有効なまま
+    db.assign_role(account_id, "viewer").expect("Failed to assign role");
+    db.unassign_role(account_id, "developer").expect("Failed to unassign role");
+    db.transfer_service_account(account_id, "data-eng").expect("Failed to transfer service account");
+    let account = db.get_service_account(account_id).expect("Failed to get service account");
+    assert_eq!(account.team.as_deref(), Some("data-eng"));
+    assert_eq!(db.list_service_accounts(1).unwrap().len(), 1);
+    let key_data = api_key_service.validate_api_key(api_key.expose_secret()).expect("API key should still be valid");
+    assert_eq!(key_data.scopes, vec!["read".to_string()]);
+    assert!(matches!(
+        db.transfer_service_account(user_key.user_id, "data-eng"),
+        Err(ApiError::ServiceAccountNotFound)
+    ));
+    
+    println!("✅ Service account keys test passed");
+}
+
+#[tokio::test]
+async fn test_multi_environment_keys() {
+    println!("🧪 Testing keys for multiple environments and prefixes...");
+    
+    let (db, api_key_service, dev_api_key) = setup("test_multi_environment", "environment_user");
+    let dev_key = api_key_service.validate_api_key(&dev_api_key).expect("API key should be valid");
+    let api_key_service = api_key_service
+        .with_environments(vec!["prod".to_string(), "staging".to_string()])
+        .with_accepted_prefixes(vec!["legacy".to_string()]);
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    
+    // 作成時に環境（とプレフィックス）を選べる
+    let create_key = |prefix: &str, environment: &str| {
+        let (api_key, key_hash) = api_key_service.generate_api_key_for(prefix, environment)
+            .expect("Failed to generate API key");
+        db.create_api_key(dev_key.user_id, &key_hash, prefix, environment, 1, &[String::from("read")], None)
+            .expect("Failed to create API key");
+        api_key
+    };
+    let prod_api_key = create_key("test", "prod");
+    let legacy_api_key = create_key("legacy", "staging");
+    assert!(prod_api_key.expose_secret().starts_with("test_prod_"));
+    
+    let prod_key = api_key_service.validate_api_key(prod_api_key.expose_secret()).expect("Prod key should be valid");
+    assert_eq!(prod_key.environment, "prod");
+    assert_eq!(api_key_service.validate_api_key(legacy_api_key.expose_secret()).unwrap().key_prefix, "legacy");
+    assert!(api_key_service.validate_api_key(&dev_api_key).is_ok());
+    
+    // 設定にない環境・プレフィックスでは発行も検証もできない
+    assert!(matches!(api_key_service.generate_api_key_for("test", "qa"), Err(ApiError::InvalidRequest(_))));
+    assert!(matches!(api_key_service.generate_api_key_for("other", "prod"), Err(ApiError::InvalidRequest(_))));
+    let dev_only = ApiKeyService::new(db.clone(), "test".to_string(), "dev".to_string(), "test_secret_key".to_string());
+    assert!(matches!(dev_only.validate_api_key(prod_api_key.expose_secret()), Err(ApiError::InvalidKeyFormat)));
+    
+    // アクセストークンは発行元キーの環境を持つ
+    let pair = token_service.issue_token_pair(&prod_key).expect("Failed to issue token pair");
+    let claims = token_service.validate_access_token(pair.access_token.expose_secret()).expect("Token should be valid");
+    assert_eq!(claims.environment.as_deref(), Some("prod"));
+    
+    // ローテーション後のキーも同じ環境・プレフィックスで発行される
+    let rotated = api_key_service.rotate_api_key(prod_key.id, None).expect("Failed to rotate API key");
+    assert!(rotated.api_key.expose_secret().starts_with("test_prod_"));
+    assert_eq!(api_key_service.validate_api_key(rotated.api_key.expose_secret()).unwrap().environment, "prod");
+    
+    println!("✅ Multi-environment keys test passed");
+}
+
+#[tokio::test]
+async fn test_v2_key_format() {
+    println!("🧪 Testing v2 key format and offline checksum...");
+    
+    let (db, api_key_service, api_key) = setup("test_v2_key_format", "v2_user");
+    assert_eq!(api_key_service.key_format(), KeyFormat::V2);
+    
+    // v2キーは prefix_env_v2_<base62 256ビット>_<CRC32> の5要素
+    let parts: Vec<&str> = api_key.split('_').collect();
+    assert_eq!(parts.len(), 5);
+    assert_eq!((parts[0], parts[1], parts[2]), ("test", "dev", "v2"));
+    assert_eq!(parts[3].len(), 43);
+    assert_eq!(parts[4].len(), 6);
+    assert!(parts[3].chars().chain(parts[4].chars()).all(|c| c.is_ascii_alphanumeric()));
+    
+    // サーバーの設定がなくても、キーだけで形式とチェックサムを確認できる
+    assert!(check_v2_key(&api_key).is_ok());
+    assert!(check_v2_key("ghp_live_v2_0000000000000000000000000000000000000000000_000000").is_err());
+    let mut tampered = api_key.clone().into_bytes();
+    let index = parts[0].len() + parts[1].len() + parts[2].len() + 3;
+    tampered[index] = if tampered[index] == b'A' { b'B' } else { b'A' };
+    let tampered = String::from_utf8(tampered).unwrap();
+    assert!(matches!(check_v2_key(&tampered), Err(ApiError::InvalidChecksum)));
+    assert!(matches!(api_key_service.validate_api_key(&tampered), Err(ApiError::InvalidChecksum)));
+    assert!(matches!(check_v2_key(&format!("{}0", api_key)), Err(ApiError::InvalidKeyFormat)));
+    assert!(matches!(check_v2_key(&api_key.replacen("test_", "", 1)), Err(ApiError::InvalidKeyFormat)));
+    // 形式の印がないキーや別の版の印を持つキーはv2として扱わない
+    assert!(matches!(check_v2_key(&api_key.replacen("_v2_", "_", 1)), Err(ApiError::InvalidKeyFormat)));
+    assert!(matches!(check_v2_key(&api_key.replacen("_v2_", "_v3_", 1)), Err(ApiError::InvalidKeyFormat)));
+    for marker in ["_v2r_", "_v2r1_", "_v2rx_", "_v2r+3_"] {
+        assert!(matches!(check_v2_key(&api_key.replacen("_v2_", marker, 1)), Err(ApiError::InvalidKeyFormat)));
+    }
+    assert!(matches!(api_key_service.validate_api_key_format(&api_key.replacen("_v2_", "_v1_", 1)), Err(ApiError::InvalidKeyFormat)));
+    
+    // 別のプレフィックスのv2キーもオフラインでは検証できる
+    let other_service = ApiKeyService::new(db.clone(), "acme".to_string(), "prod".to_string(), "other_secret".to_string());
+    let (other_key, _) = other_service.generate_api_key().expect("Failed to generate API key");
+    assert!(check_v2_key(other_key.expose_secret()).is_ok());
+    assert!(matches!(api_key_service.validate_api_key_format(other_key.expose_secret()), Err(ApiError::InvalidKeyFormat)));
+    
+    // v1キーも引き続き受け付ける
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    let v1_service = api_key_service.clone().with_key_format(KeyFormat::V1);
+    let (v1_key, v1_hash) = v1_service.generate_api_key().expect("Failed to generate API key");
+    assert!(v1_key.expose_secret().starts_with("test_dev_v1_"));
+    assert!(matches!(check_v2_key(v1_key.expose_secret()), Err(ApiError::InvalidKeyFormat)));
+    db.create_api_key(key_data.user_id, &v1_hash, "test", "dev", 1, &[String::from("read")], None)
+        .expect("Failed to create API key");
+    assert!(api_key_service.validate_api_key(v1_key.expose_secret()).is_ok());
+    
+    // 以前のローテーションで発行された、回数を v2, v3 として持つv1キーも受け付ける
+    let alphabet = base32::Alphabet::RFC4648 { padding: false };
+    let random = [7u8; 20];
+    let checksum = Sha256::new()
+        .chain_update("test").chain_update("dev").chain_update("v2").chain_update("1700000000")
+        .chain_update(random)
+        .finalize();
+    let legacy_v1_key = format!(
+        "test_dev_v2_1700000000_{}_{}",
+        base32::encode(alphabet, &random),
+        base32::encode(alphabet, &checksum[..4])
+    );
+    assert!(api_key_service.validate_api_key_format(&legacy_v1_key).is_ok());
+    assert!(matches!(check_v2_key(&legacy_v1_key), Err(ApiError::InvalidKeyFormat)));
+    
+    // v1キーをローテーションするとv2キーが発行され、バージョンは v2r2 としてキーに現れる
+    let v1_data = api_key_service.validate_api_key(v1_key.expose_secret()).unwrap();
+    let rotated = api_key_service.rotate_api_key(v1_data.id, None).expect("Failed to rotate API key");
+    assert!(rotated.api_key.expose_secret().starts_with("test_dev_v2r2_"));
+    assert!(check_v2_key(rotated.api_key.expose_secret()).is_ok());
+    assert!(api_key_service.validate_api_key(rotated.api_key.expose_secret()).is_ok());
+    assert_eq!(rotated.key.version, 2);
+    let rotated = api_key_service.rotate_api_key(rotated.key.id, None).expect("Failed to rotate API key");
+    assert!(rotated.api_key.expose_secret().starts_with("test_dev_v2r3_"));
+    assert!(api_key_service.validate_api_key(rotated.api_key.expose_secret()).is_ok());
+    assert_eq!(rotated.key.version, 3);
+    
+    println!("✅ v2 key format test passed");
+}
+
+#[tokio::test]
+async fn test_peppered_credential_hashes() {
+    println!("🧪 Testing peppered key and token hashes...");
+    
+    let (db, legacy_service, legacy_api_key) = setup("test_peppered_hashes", "pepper_user");
+    let legacy_key = legacy_service.validate_api_key(&legacy_api_key).expect("API key should be valid");
+    assert_eq!(legacy_key.hash_algorithm, "sha256");
+    let legacy_tokens = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let legacy_pair = legacy_tokens.issue_token_pair(&legacy_key).expect("Failed to issue token pair");
+    
+    let api_key_service = legacy_service.clone().with_pepper(b"server-pepper".to_vec());
+    let token_service = legacy_tokens.clone().with_pepper(b"server-pepper".to_vec());
+    assert_eq!(api_key_service.hash_algorithm(), "hmac-sha256");
+    
+    // SHA-256で保存された既存キーは検証でき、成功時にHMACで再ハッシュされる
+    let key_data = api_key_service.validate_api_key(&legacy_api_key).expect("Legacy key should still be valid");
+    assert_eq!(key_data.hash_algorithm, "hmac-sha256");
+    let stored = db.get_api_key(key_data.id).unwrap();
+    assert_eq!(stored.hash_algorithm, "hmac-sha256");
+    assert_ne!(stored.key_hash, legacy_key.key_hash);
+    assert!(api_key_service.validate_api_key(&legacy_api_key).is_ok());
+    
+    // ペッパーなし・別のペッパーでは再ハッシュ後のキーを確認できない
+    assert!(matches!(legacy_service.validate_api_key(&legacy_api_key), Err(ApiError::KeyNotFound)));
+    let other_pepper = legacy_service.clone().with_pepper(b"other-pepper".to_vec());
+    assert!(matches!(other_pepper.validate_api_key(&legacy_api_key), Err(ApiError::KeyNotFound)));
+    
+    // 新しいキーは最初からHMACで保存される
+    let (api_key, key_hash) = api_key_service.generate_api_key().expect("Failed to generate API key");
+    let key_id = db.create_api_key_with_hash_algorithm(
+        key_data.user_id, &key_hash, api_key_service.hash_algorithm(), "test", "dev", 1, &[String::from("read")], None,
+    ).expect("Failed to create API key");
+    assert_eq!(api_key_service.validate_api_key(api_key.expose_secret()).unwrap().id, key_id);
+    assert!(matches!(legacy_service.validate_api_key(api_key.expose_secret()), Err(ApiError::KeyNotFound)));
+    
+    // ペッパー導入前のリフレッシュトークンも交換でき、新しいトークンはHMACで保存される
+    let pair = token_service.refresh_access_token(legacy_pair.refresh_token.expose_secret())
+        .expect("Legacy refresh token should still be accepted");
+    assert!(matches!(legacy_tokens.refresh_access_token(pair.refresh_token.expose_secret()), Err(ApiError::InvalidToken)));
+    token_service.refresh_access_token(pair.refresh_token.expose_secret()).expect("Failed to refresh token");
+    
+    println!("✅ Peppered credential hashes test passed");
+}
+
+#[tokio::test]
+async fn test_credential_hash_migration() {
+    println!("🧪 Testing credential hash algorithm migration...");
+    
+    let (db, legacy_service, api_key) = setup("test_hash_migration", "migration_user");
+    let legacy_key = legacy_service.validate_api_key(&api_key).expect("API key should be valid");
+    let legacy_tokens = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let legacy_pair = legacy_tokens.issue_token_pair(&legacy_key).expect("Failed to issue token pair");
+    
+    // アルゴリズムIDは保存用の文字列と相互変換できる
+    let wrapped: HashScheme = "hmac-sha256(sha256)".parse().unwrap();
+    assert_eq!(wrapped, HashScheme::wrapping(HashAlgorithm::HmacSha256, HashAlgorithm::Sha256));
+    assert_eq!(wrapped.to_string(), "hmac-sha256(sha256)");
+    assert_eq!("blake3-keyed".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::Blake3Keyed);
+    assert!("md5".parse::<HashScheme>().is_err());
+    // 鍵付きアルゴリズムにはペッパーが必要
+    assert!(CredentialHashing::new(HashAlgorithm::Blake3Keyed, None).is_err());
+    
+    // ドライランでは何も書き換えない
+    let hmac = CredentialHashing::hmac(b"server-pepper");
+    let reports = migrate_credential_hashes(&db, &hmac, true).expect("Dry run failed");
+    assert_eq!(reports[0].table, "api_keys");
+    assert_eq!(reports[0].upgraded, 1);
+    assert_eq!(db.get_api_key(legacy_key.id).unwrap().hash_algorithm, "sha256");
+    
+    // SHA-256のハッシュはキーを知らなくてもHMACで包んで移行できる
+    let reports = migrate_credential_hashes(&db, &hmac, false).expect("Migration failed");
+    assert!(reports.iter().all(|report| report.flagged.is_empty()));
+    let stored = db.get_api_key(legacy_key.id).unwrap();
+    assert_eq!(stored.hash_algorithm, "hmac-sha256(sha256)");
+    assert!(matches!(legacy_service.validate_api_key(&api_key), Err(ApiError::KeyNotFound)));
+    
+    // 移行後のキーは検証でき、成功時に包まれていないHMACへ再ハッシュされる
+    let api_key_service = legacy_service.clone().with_hashing(hmac.clone());
+    let key_data = api_key_service.validate_api_key(&api_key).expect("Migrated key should be valid");
+    assert_eq!(key_data.hash_algorithm, "hmac-sha256");
+    assert_eq!(db.get_api_key(legacy_key.id).unwrap().key_hash, hmac.hash(&api_key));
+    
+    // 移行済みのリフレッシュトークンも交換できる
+    let token_service = legacy_tokens.clone().with_hashing(hmac.clone());
+    token_service.refresh_access_token(legacy_pair.refresh_token.expose_secret())
+        .expect("Migrated refresh token should be accepted");
+    
+    // 鍵付きアルゴリズム同士は包めないため、再ハッシュ待ちとしてフラグを立てる
+    let blake3 = CredentialHashing::new(HashAlgorithm::Blake3Keyed, Some(b"server-pepper".as_slice()))
+        .expect("BLAKE3 should be available with a pepper");
+    let reports = migrate_credential_hashes(&db, &blake3, false).expect("Migration failed");
+    assert_eq!(reports[0].flagged, vec![legacy_key.id]);
+    assert_eq!(db.list_flagged_credentials("api_keys").unwrap(), vec![legacy_key.id]);
+    
+    // フラグ付きのキーは次の検証で新しいアルゴリズムに再ハッシュされ、フラグが消える
+    let blake3_service = legacy_service.clone().with_hashing(blake3.clone());
+    let key_data = blake3_service.validate_api_key(&api_key).expect("Flagged key should still be valid");
+    assert_eq!(key_data.hash_algorithm, "blake3-keyed");
+    assert!(db.list_flagged_credentials("api_keys").unwrap().is_empty());
+    let reports = migrate_credential_hashes(&db, &blake3, true).expect("Dry run failed");
+    assert_eq!(reports[0].current, 1);
+    assert!(db.list_credential_hashes("users").is_err());
+    
+    println!("✅ Credential hash migration test passed");
+}
+
+#[tokio::test]
+async fn test_secrets_are_redacted() {
+    println!("🧪 Testing that keys and tokens are never printed...");
+    
+    let (db, api_key_service, api_key) = setup("test_secret_redaction", "redaction_user");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    
+    // 生成したキーは表示・デバッグ出力ともに伏せられる
+    let (generated, _) = api_key_service.generate_api_key().expect("Failed to generate API key");
+    assert_eq!(generated.to_string(), "[REDACTED]");
+    assert_eq!(format!("{:?}", generated), "SecretString([REDACTED])");
+    assert!(generated.expose_secret().starts_with("test_dev_"));
+    assert_eq!(api_key_service.secret_key().to_string(), "[REDACTED]");
+    
+    // トークンペアをログに出してもトークンは含まれない
+    let key_data = api_key_service.validate_api_key(&api_key).expect("API key should be valid");
+    let pair = token_service.issue_token_pair(&key_data).expect("Failed to issue token pair");
+    let logged = format!("{:?}", pair);
+    assert!(!logged.contains(pair.access_token.expose_secret()));
+    assert!(!logged.contains(pair.refresh_token.expose_secret()));
+    assert!(logged.contains("Bearer"));
+    
+    // リクエストボディから読み込んだ秘密情報も伏せられる
+    let request: RefreshTokenRequest = serde_json::from_value(serde_json::json!({
+        "refresh_token": pair.refresh_token.expose_secret(),
+    })).unwrap();
+    assert_eq!(request.refresh_token, pair.refresh_token);
+    assert!(!format!("{:?}", request).contains(pair.refresh_token.expose_secret()));
+    
+    // 改ざんしたキー・トークンのエラーメッセージにも含まれない
+    let tampered_key = format!("{}A", api_key);
+    let error = api_key_service.validate_api_key(&tampered_key).unwrap_err().to_string();
+    assert!(!error.contains(&tampered_key));
+    let tampered_token = format!("{}A", pair.access_token.expose_secret());
+    let error = token_service.validate_access_token(&tampered_token).unwrap_err().to_string();
+    assert!(!error.contains(&tampered_token));
+    assert_ne!(SecretString::from("rt_a"), SecretString::from("rt_b"));
+    
+    println!("✅ Secret redaction test passed");
+}
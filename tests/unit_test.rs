@@ -1,7 +1,10 @@
 use std::fs;
 use std::path::Path;
+use chrono::{Duration, Utc};
 use secure_api_key::{
     database::Database,
+    errors::ApiError,
+    models::{ApiKeyDetails, ApiKeyFilter, ApiKeyStatus, Scope, ScopeSet},
     security::TokenService,
 };
 
@@ -107,7 +110,7 @@ async fn test_token_generation_and_validation() {
     assert!(!token.is_empty());
     
     // トークン検証
-    let validation_result = token_service.validate_access_token(&token);
+    let validation_result = token_service.validate_access_token(token.expose_secret());
     assert!(validation_result.is_ok());
     
     let claims = validation_result.unwrap();
@@ -115,4 +118,651 @@ async fn test_token_generation_and_validation() {
     assert_eq!(claims.api_key_id, api_key_id);
     
     println!("✅ Token generation and validation test passed");
-} 
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_database_timestamps() {
+    println!("🧪 Testing database timestamp persistence...");
+    
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/test_timestamps.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+    
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let before = Utc::now() - Duration::seconds(1);
+    
+    let user_id = db.create_user("timestamp_user", "timestamp_user@example.com")
+        .expect("Failed to create user");
+    let api_key_id = db.create_api_key(
+        user_id,
+        "test_timestamps_hash",
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        Some(Utc::now() + Duration::days(1)),
+    ).expect("Failed to create API key");
+    db.update_api_key_usage(api_key_id).expect("Failed to update usage");
+    
+    // 読み出した時刻が保存時の実際の値であること
+    let user = db.get_user(user_id).expect("Failed to get user");
+    assert!(user.created_at >= before && user.created_at <= Utc::now());
+    
+    let api_key = db.get_api_key(api_key_id).expect("Failed to get API key");
+    assert!(api_key.issued_at >= before && api_key.issued_at <= Utc::now());
+    let last_used_at = api_key.last_used_at.expect("last_used_at should be set");
+    assert!(last_used_at >= api_key.issued_at);
+    assert_eq!(api_key.usage_count, 1);
+    
+    let token_expires_at = Utc::now() + Duration::hours(1);
+    db.create_access_token(api_key_id, "test_timestamps_jti", None, "test_timestamps_token_hash", "sha256", token_expires_at)
+        .expect("Failed to create access token");
+    let token = db.get_access_token_by_hash("test_timestamps_token_hash")
+        .expect("Failed to get access token");
+    assert_eq!(token.expires_at.timestamp_millis(), token_expires_at.timestamp_millis());
+    assert!(token.issued_at >= before && token.issued_at <= token.expires_at);
+    
+    println!("✅ Database timestamp persistence test passed");
+}
+
+#[tokio::test]
+async fn test_database_timestamp_migration() {
+    println!("🧪 Testing legacy timestamp migration...");
+    
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+    
+    let db_path = format!("{}/test_timestamp_migration.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+    
+    // 旧バージョンが書き込んだ形式（CURRENT_TIMESTAMP と +00:00 付き RFC3339）を再現
+    {
+        let db = Database::new(&db_path).expect("Failed to create test database");
+        let user_id = db.create_user("legacy_user", "legacy_user@example.com")
+            .expect("Failed to create user");
+        db.create_api_key(
+            user_id,
+            "test_legacy_hash",
+            "test",
+            "dev",
+            1,
+            &[String::from("read")],
+            None,
+        ).expect("Failed to create API key");
+    }
+    {
+        let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+        conn.execute_batch(
+            "UPDATE users SET created_at = '2024-01-02 03:04:05', updated_at = '2024-01-02 03:04:05';
+             UPDATE api_keys SET issued_at = '2024-01-02 03:04:05', expires_at = '2024-02-01T12:00:00+09:00';
+             PRAGMA user_version = 0;",
+        ).expect("Failed to write legacy timestamps");
+    }
+    
+    // 再オープン時に正規形式へ移行される
+    let db = Database::new(&db_path).expect("Failed to reopen test database");
+    let user = db.get_user(1).expect("Failed to get user");
+    assert_eq!(user.created_at.to_rfc3339(), "2024-01-02T03:04:05+00:00");
+    
+    let api_key = db.get_api_key_by_hash("test_legacy_hash").expect("Failed to get API key");
+    assert_eq!(api_key.issued_at.to_rfc3339(), "2024-01-02T03:04:05+00:00");
+    assert_eq!(
+        api_key.expires_at.map(|dt| dt.to_rfc3339()),
+        Some("2024-02-01T03:00:00+00:00".to_string())
+    );
+    
+    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+    let stored: String = conn
+        .query_row("SELECT expires_at FROM api_keys WHERE key_hash = 'test_legacy_hash'", [], |row| row.get(0))
+        .expect("Failed to read raw timestamp");
+    assert_eq!(stored, "2024-02-01T03:00:00.000Z");
+    
+    println!("✅ Legacy timestamp migration test passed");
+}
+
+#[tokio::test]
+async fn test_scope_matching() {
+    println!("🧪 Testing scope grammar and matching...");
+
+    let scope = |s: &str| Scope::parse(s).expect("Failed to parse scope");
+    let set = |scopes: &[&str]| ScopeSet::parse(scopes).expect("Failed to parse scopes");
+
+    // 文法: action / resource:action / resource:* / *
+    assert_eq!(scope("billing:read").resource(), Some("billing"));
+    assert_eq!(scope("billing:read").action(), "read");
+    assert_eq!(scope("read").resource(), None);
+    assert!(scope("billing:*").is_wildcard());
+    for invalid in ["", "billing:", ":read", "a:b:c", "bill ing:read", "billing:re*d"] {
+        assert!(matches!(Scope::parse(invalid), Err(ApiError::InvalidRequest(_))), "{:?} should be rejected", invalid);
+    }
+    assert_eq!(set(&["read", "read", "billing:*"]).to_strings(), vec!["read", "billing:*"]);
+
+    // ワイルドカードはリソース内の全アクションを許可する
+    let billing = set(&["billing:*"]);
+    assert!(billing.satisfies(&set(&["billing:read", "billing:refund"])));
+    assert!(!billing.satisfies(&set(&["reports:read"])));
+    assert!(!billing.satisfies(&set(&["read"])));
+
+    // 階層: admin ⊃ write ⊃ read
+    let admin = set(&["billing:admin"]);
+    assert!(admin.satisfies(&set(&["billing:read", "billing:write"])));
+    assert!(!admin.satisfies(&set(&["billing:refund"])));
+    assert!(!set(&["billing:read"]).satisfies(&set(&["billing:write"])));
+
+    // リソースなしのアクションは全リソースに適用される
+    let global = set(&["write"]);
+    assert!(global.satisfies(&set(&["read", "billing:read", "reports:write"])));
+    assert!(!global.satisfies(&set(&["admin"])));
+    assert!(set(&["*"]).satisfies(&set(&["admin", "billing:*"])));
+    assert_eq!(global.missing(&set(&["read", "admin", "billing:admin"])).to_strings(), vec!["admin", "billing:admin"]);
+
+    // 共通部分: 両方の集合が許可するスコープ
+    assert_eq!(set(&["billing:*", "admin"]).intersect(&set(&["write"])).to_strings(), vec!["billing:write", "write"]);
+    assert_eq!(set(&["billing:refund"]).intersect(&set(&["*"])).to_strings(), vec!["billing:refund"]);
+    assert!(set(&["billing:refund"]).intersect(&set(&["admin", "reports:*"])).is_empty());
+
+    println!("✅ Scope matching test passed");
+}
+
+#[tokio::test]
+async fn test_token_scope_enforcement() {
+    println!("🧪 Testing scope checks on issued tokens...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_token_scopes.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let user_id = db.create_user("scope_user_unit", "scope_user_unit@example.com")
+        .expect("Failed to create user");
+    let api_key_id = db.create_api_key(
+        user_id,
+        "test_token_scopes_hash",
+        "test",
+        "dev",
+        1,
+        &[String::from("billing:*")],
+        None,
+    ).expect("Failed to create API key");
+    for name in ["billing:read", "billing:refund"] {
+        db.register_scope(&Scope::parse(name).unwrap(), "billing").expect("Failed to register scope");
+    }
+    db.assign_role(user_id, "admin").expect("Failed to assign role");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+
+    // 不正なスコープではトークンを発行しない
+    let result = token_service.generate_access_token(user_id, api_key_id, vec!["billing read".to_string()]);
+    assert!(matches!(result, Err(ApiError::InvalidRequest(_))));
+
+    let token = token_service.generate_access_token(user_id, api_key_id, vec!["billing:*".to_string()])
+        .expect("Failed to generate token");
+    let claims = token_service.validate_access_token(token.expose_secret()).expect("Token should be valid");
+    assert!(claims.require_scopes(&ScopeSet::parse(&["billing:refund"]).unwrap()).is_ok());
+
+    let result = claims.require_scopes(&ScopeSet::parse(&["billing:read", "reports:read"]).unwrap());
+    match result {
+        Err(ApiError::InsufficientScope(missing)) => assert_eq!(missing, "reports:read"),
+        other => panic!("Expected InsufficientScope, got {:?}", other),
+    }
+
+    println!("✅ Token scope enforcement test passed");
+}
+
+#[tokio::test]
+async fn test_scope_registry() {
+    println!("🧪 Testing scope registry...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_scope_registry.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let set = |scopes: &[&str]| ScopeSet::parse(scopes).expect("Failed to parse scopes");
+
+    // 組み込みスコープは最初から登録されている
+    let names: Vec<String> = db.list_scopes().expect("Failed to list scopes")
+        .into_iter().map(|scope| scope.name).collect();
+    assert_eq!(names, vec!["admin", "platform:admin", "read", "write"]);
+
+    let reports_read = Scope::parse("reports:read").unwrap();
+    db.register_scope(&reports_read, "Read reports").expect("Failed to register scope");
+    assert!(matches!(db.register_scope(&reports_read, "again"), Err(ApiError::InvalidRequest(_))));
+
+    // 未登録のスコープはすべて列挙して拒否する
+    assert!(db.check_scopes_registered(&set(&["read", "reports:read", "reports:*"])).is_ok());
+    match db.check_scopes_registered(&set(&["reports:read", "raed", "billing:*"])) {
+        Err(ApiError::InvalidRequest(message)) => assert_eq!(message, "Unknown scopes: raed, billing:*"),
+        other => panic!("Expected InvalidRequest, got {:?}", other),
+    }
+
+    // トークン発行時も同じ検証を行う
+    let user_id = db.create_user("registry_user_unit", "registry_user_unit@example.com")
+        .expect("Failed to create user");
+    let api_key_id = db.create_api_key(
+        user_id,
+        "test_scope_registry_hash",
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        None,
+    ).expect("Failed to create API key");
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let result = token_service.generate_access_token(user_id, api_key_id, vec!["reports:write".to_string()]);
+    assert!(matches!(result, Err(ApiError::InvalidRequest(message)) if message == "Unknown scopes: reports:write"));
+
+    println!("✅ Scope registry test passed");
+}
+
+#[tokio::test]
+async fn test_role_migration() {
+    println!("🧪 Testing role assignment for existing users...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_role_migration.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    // 新規ユーザーにはデフォルトロールが付与される
+    let user_id = {
+        let db = Database::new(&db_path).expect("Failed to create test database");
+        let user_id = db.create_user("role_legacy_user", "role_legacy_user@example.com")
+            .expect("Failed to create user");
+        let roles = db.get_user_roles(user_id).expect("Failed to get roles");
+        assert_eq!(roles.iter().map(|role| role.name.as_str()).collect::<Vec<_>>(), vec!["developer"]);
+        user_id
+    };
+
+    // ロール導入前のユーザーを再現
+    {
+        let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+        conn.execute_batch("DELETE FROM user_roles; PRAGMA user_version = 1;")
+            .expect("Failed to simulate legacy database");
+    }
+
+    // 既存ユーザーは制限なしのadminロールに移行される
+    let db = Database::new(&db_path).expect("Failed to reopen test database");
+    let roles = db.get_user_roles(user_id).expect("Failed to get roles");
+    assert_eq!(roles.iter().map(|role| role.name.as_str()).collect::<Vec<_>>(), vec!["admin"]);
+    assert_eq!(db.get_user_role_scopes(user_id).unwrap().to_strings(), vec!["*"]);
+
+    // 移行は一度だけ: ロールを外しても再付与されない
+    assert!(db.unassign_role(user_id, "admin").expect("Failed to unassign role"));
+    let db = Database::new(&db_path).expect("Failed to reopen test database");
+    assert!(db.get_user_roles(user_id).expect("Failed to get roles").is_empty());
+    assert!(matches!(db.assign_role(user_id, "owner"), Err(ApiError::RoleNotFound)));
+    assert!(matches!(db.assign_role(9999, "viewer"), Err(ApiError::UserNotFound)));
+
+    println!("✅ Role migration test passed");
+}
+
+#[tokio::test]
+async fn test_organization_roles() {
+    println!("🧪 Testing roles scoped to organizations...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_organization_roles.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let acme = db.create_organization("acme").expect("Failed to create organization");
+    let globex = db.create_organization("globex").expect("Failed to create organization");
+    let globex_user = db.create_user_in_organization(globex, "globex_dev", "dev@globex.example")
+        .expect("Failed to create user");
+    let read = ScopeSet::parse(&["read".to_string()]).unwrap();
+
+    // ロール名は組織ごとに一意で、組み込みロールの名前は使えない
+    db.create_role(acme, "auditor", "Audit access", &read).expect("Failed to create role");
+    db.create_role(acme, "billing", "Billing access", &read).expect("Failed to create role");
+    assert!(matches!(db.create_role(acme, "auditor", "again", &read), Err(ApiError::InvalidRequest(_))));
+    assert!(matches!(db.create_role(acme, "admin", "shadow", &read), Err(ApiError::InvalidRequest(_))));
+    let globex_auditor = db.create_role(globex, "auditor", "Audit access", &read)
+        .expect("Same name in another organization should be allowed");
+
+    // 一覧には組み込みロールと自組織のロールだけが含まれる
+    let names = |organization_id: i64| -> Vec<String> {
+        db.list_roles(organization_id).expect("Failed to list roles")
+            .into_iter().map(|role| role.name).collect()
+    };
+    assert_eq!(names(acme), vec!["admin", "auditor", "billing", "developer", "viewer"]);
+    assert_eq!(names(globex), vec!["admin", "auditor", "developer", "viewer"]);
+
+    // 同名のロールはユーザーの組織のものが割り当てられ、他組織のロールは見つからない
+    db.assign_role(globex_user, "auditor").expect("Failed to assign role");
+    let roles = db.get_user_roles(globex_user).expect("Failed to get roles");
+    assert!(roles.iter().any(|role| role.id == globex_auditor && role.organization_id == Some(globex)));
+    assert!(matches!(db.assign_role(globex_user, "billing"), Err(ApiError::RoleNotFound)));
+
+    // 再オープンしても組み込みロールは重複しない
+    let db = Database::new(&db_path).expect("Failed to reopen test database");
+    assert_eq!(db.list_roles(acme).unwrap().len(), 5);
+
+    // 名前が全体で一意だった旧テーブルのロールは、デフォルト組織に移行される
+    {
+        let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             DELETE FROM user_roles WHERE role_id IN (SELECT id FROM roles WHERE organization_id IS NOT NULL);
+             DELETE FROM roles WHERE organization_id IS NOT NULL;
+             DROP INDEX idx_roles_organization_name;
+             CREATE TABLE roles_legacy (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT UNIQUE NOT NULL, description TEXT NOT NULL, scopes TEXT NOT NULL, created_at DATETIME);
+             INSERT INTO roles_legacy SELECT id, name, description, scopes, created_at FROM roles;
+             INSERT INTO roles_legacy (name, description, scopes, created_at) VALUES ('legacy-ops', 'Operations', '[\"write\"]', '2024-01-01T00:00:00.000Z');
+             DROP TABLE roles;
+             ALTER TABLE roles_legacy RENAME TO roles;",
+        ).expect("Failed to simulate legacy database");
+    }
+    let db = Database::new(&db_path).expect("Failed to reopen test database");
+    let roles = db.list_roles(1).expect("Failed to list roles");
+    assert_eq!(roles.iter().map(|role| role.name.as_str()).collect::<Vec<_>>(), vec!["admin", "developer", "legacy-ops", "viewer"]);
+    assert!(roles.iter().all(|role| role.organization_id == (role.name == "legacy-ops").then_some(1)));
+    assert_eq!(db.list_roles(acme).unwrap().len(), 3);
+    let roles = db.get_user_roles(globex_user).expect("Failed to get roles");
+    assert_eq!(roles.iter().map(|role| role.name.as_str()).collect::<Vec<_>>(), vec!["developer"]);
+
+    println!("✅ Organization roles test passed");
+}
+
+#[tokio::test]
+async fn test_organization_isolation() {
+    println!("🧪 Testing organization isolation...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_organizations.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let acme = db.create_organization("acme").expect("Failed to create organization");
+    let globex = db.create_organization("globex").expect("Failed to create organization");
+    assert!(matches!(db.create_organization("acme"), Err(ApiError::InvalidRequest(_))));
+    assert!(matches!(
+        db.create_user_in_organization(9999, "nobody", "nobody@example.com"),
+        Err(ApiError::OrganizationNotFound)
+    ));
+
+    // ユーザーとキーはそれぞれの組織に属する
+    let acme_user = db.create_user_in_organization(acme, "acme_admin", "admin@acme.example")
+        .expect("Failed to create user");
+    let globex_user = db.create_user_in_organization(globex, "globex_admin", "admin@globex.example")
+        .expect("Failed to create user");
+    assert_eq!(db.get_user(acme_user).unwrap().organization_id, acme);
+    assert!(db.get_principal_in_organization(acme, acme_user).is_ok());
+    assert!(matches!(db.get_principal_in_organization(acme, globex_user), Err(ApiError::UserNotFound)));
+
+    let create_key = |user_id: i64, hash: &str| db.create_api_key(
+        user_id,
+        hash,
+        "test",
+        "dev",
+        1,
+        &[String::from("read")],
+        None,
+    ).expect("Failed to create API key");
+    let acme_key = create_key(acme_user, "test_org_acme_hash");
+    let globex_key = create_key(globex_user, "test_org_globex_hash");
+    assert_eq!(db.get_api_key(acme_key).unwrap().organization_id, acme);
+    assert!(matches!(
+        db.create_api_key(9999, "test_org_orphan_hash", "test", "dev", 1, &[], None),
+        Err(ApiError::UserNotFound)
+    ));
+
+    // 他の組織のキーは存在しないものとして扱う
+    assert!(db.get_api_key_for_organization(acme, acme_key).is_ok());
+    assert!(matches!(db.get_api_key_for_organization(acme, globex_key), Err(ApiError::KeyNotFound)));
+    let listed: Vec<i64> = db.list_api_keys_for_organization(globex).expect("Failed to list keys")
+        .into_iter().map(|key| key.id).collect();
+    assert_eq!(listed, vec![globex_key]);
+
+    // トークンのクレームに組織IDが含まれる
+    let token_service = TokenService::new(db.clone(), "test_secret_key".to_string());
+    let token = token_service.generate_access_token(globex_user, globex_key, vec!["read".to_string()])
+        .expect("Failed to generate token");
+    let claims = token_service.validate_access_token(token.expose_secret()).expect("Token should be valid");
+    assert_eq!(claims.organization_id, Some(globex));
+
+    println!("✅ Organization isolation test passed");
+}
+
+#[tokio::test]
+async fn test_users_table_rebuild_migration() {
+    println!("🧪 Testing users table migration for service accounts...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_users_rebuild.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    // サービスアカウント導入前の users テーブル（email が NOT NULL）を再現
+    {
+        let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+        conn.execute_batch(
+            "CREATE TABLE users (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 username TEXT UNIQUE NOT NULL,
+                 email TEXT UNIQUE NOT NULL,
+                 created_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+                 updated_at DATETIME DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+             );
+             INSERT INTO users (username, email) VALUES ('legacy_person', 'legacy_person@example.com');",
+        ).expect("Failed to create legacy users table");
+    }
+
+    // 既存ユーザーは保持され、email なしのサービスアカウントを作成できる
+    let db = Database::new(&db_path).expect("Failed to migrate database");
+    let user = db.get_user(1).expect("Failed to get migrated user");
+    assert_eq!(user.email, "legacy_person@example.com");
+    assert_eq!(user.organization_id, 1);
+    let account_id = db.create_service_account(1, "nightly-export", None)
+        .expect("Failed to create service account");
+    assert_eq!(db.get_service_account(account_id).unwrap().name, "nightly-export");
+
+    println!("✅ Users table migration test passed");
+}
+
+#[tokio::test]
+async fn test_api_key_listing() {
+    println!("🧪 Testing API key listing and filtering...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_key_listing.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let owner = db.create_user("listing_owner", "owner@example.com").expect("Failed to create user");
+    let other = db.create_user("listing_other", "other@example.com").expect("Failed to create user");
+
+    let create_key = |user_id: i64, hash: &str, environment: &str, expires_at| db.create_api_key(
+        user_id,
+        hash,
+        "test",
+        environment,
+        1,
+        &[String::from("read")],
+        expires_at,
+    ).expect("Failed to create API key");
+    let active = create_key(owner, "test_listing_active", "dev", None);
+    let prod = create_key(owner, "test_listing_prod", "prod", None);
+    let expired = create_key(owner, "test_listing_expired", "dev", Some(Utc::now() - Duration::hours(1)));
+    let revoked = create_key(owner, "test_listing_revoked", "dev", None);
+    let rotated = create_key(owner, "test_listing_rotated", "dev", None);
+    let successor = db.rotate_api_key(rotated, "test_listing_successor", "sha256", 2, None, Utc::now() + Duration::days(1))
+        .expect("Failed to rotate key");
+    db.revoke_api_key(revoked, "test", None).expect("Failed to revoke key");
+    create_key(other, "test_listing_other", "dev", None);
+
+    let list = |filter: ApiKeyFilter| -> Vec<i64> {
+        db.list_api_keys(&filter).expect("Failed to list keys")
+            .api_keys.into_iter().map(|key| key.id).collect()
+    };
+    let for_owner = |status, environment: Option<&str>| ApiKeyFilter {
+        user_id: Some(owner),
+        status,
+        environment: environment.map(String::from),
+        ..ApiKeyFilter::default()
+    };
+
+    // ステータスと環境で絞り込む（一覧のステータスは check_usable と一致する）
+    assert_eq!(list(for_owner(None, None)), vec![active, prod, expired, revoked, rotated, successor]);
+    assert_eq!(list(for_owner(Some(ApiKeyStatus::Active), None)), vec![active, prod, successor]);
+    assert_eq!(list(for_owner(Some(ApiKeyStatus::Active), Some("prod"))), vec![prod]);
+    assert_eq!(list(for_owner(Some(ApiKeyStatus::Expired), None)), vec![expired]);
+    assert_eq!(list(for_owner(Some(ApiKeyStatus::Revoked), None)), vec![revoked]);
+    assert_eq!(list(for_owner(Some(ApiKeyStatus::Rotating), None)), vec![rotated]);
+    assert!(list(for_owner(Some(ApiKeyStatus::Inactive), None)).is_empty());
+    for status in ["active", "rotating", "expired", "revoked"] {
+        let status: ApiKeyStatus = status.parse().unwrap();
+        for key_id in list(for_owner(Some(status), None)) {
+            assert_eq!(db.get_api_key(key_id).unwrap().status(Utc::now()), status);
+        }
+    }
+    assert!(matches!("deleted".parse::<ApiKeyStatus>(), Err(ApiError::InvalidRequest(_))));
+
+    // カーソルでページを辿ると全件を重複なく取得できる
+    let mut cursor = None;
+    let mut paged = Vec::new();
+    loop {
+        let page = db.list_api_keys(&ApiKeyFilter { cursor, limit: Some(4), ..ApiKeyFilter::default() })
+            .expect("Failed to list keys");
+        assert!(page.api_keys.len() <= 4);
+        paged.extend(page.api_keys.iter().map(|key| key.id));
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(paged, list(ApiKeyFilter::default()));
+    assert_eq!(paged.len(), 7);
+
+    // 表示用にはマスクしたキーのみを返す
+    db.set_api_key_last_four(active, "WXYZ").expect("Failed to set key hint");
+    assert_eq!(db.get_api_key(active).unwrap().masked_key(), "test_dev_****WXYZ");
+    assert_eq!(db.get_api_key(prod).unwrap().masked_key(), "test_prod_****");
+
+    println!("✅ API key listing test passed");
+}
+
+#[tokio::test]
+async fn test_api_key_details() {
+    println!("🧪 Testing API key names, descriptions and labels...");
+
+    let test_db_dir = "tests/test_db";
+    if !Path::new(test_db_dir).exists() {
+        fs::create_dir_all(test_db_dir).expect("Failed to create test_db directory");
+    }
+
+    let db_path = format!("{}/test_key_details.sqlite", test_db_dir);
+    let _ = fs::remove_file(&db_path);
+
+    let db = Database::new(&db_path).expect("Failed to create test database");
+    let user_id = db.create_user("details_user", "details@example.com").expect("Failed to create user");
+    let create_key = |hash: &str| db.create_api_key(user_id, hash, "test", "dev", 1, &[String::from("read")], None)
+        .expect("Failed to create API key");
+    let ingest = create_key("test_details_ingest");
+    let billing = create_key("test_details_billing");
+    let unnamed = create_key("test_details_unnamed");
+
+    let details = |name: &str, description: &str, labels: &[(&str, &str)]| ApiKeyDetails {
+        name: Some(name.to_string()),
+        description: Some(description.to_string()),
+        labels: Some(labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+    };
+    db.update_api_key_details(ingest, &details("Staging ingest", "Pushes events from the 100% sampled pipeline", &[("team", "data"), ("env", "staging")]))
+        .expect("Failed to update key");
+    db.update_api_key_details(billing, &details("Billing export", "Nightly invoice export", &[("team", "billing")]))
+        .expect("Failed to update key");
+
+    let key = db.get_api_key(ingest).unwrap();
+    assert_eq!(key.name.as_deref(), Some("Staging ingest"));
+    assert_eq!(key.labels.get("env").map(String::as_str), Some("staging"));
+    assert!(db.get_api_key(unnamed).unwrap().labels.is_empty());
+    assert!(matches!(db.update_api_key_details(9999, &ApiKeyDetails::default()), Err(ApiError::KeyNotFound)));
+
+    // 名前・説明の部分一致（大文字小文字を区別しない）とラベルで検索できる
+    let search = |search: Option<&str>, label: Option<(&str, &str)>| -> Vec<i64> {
+        let filter = ApiKeyFilter {
+            search: search.map(String::from),
+            label: label.map(|(k, v)| (k.to_string(), v.to_string())),
+            ..ApiKeyFilter::default()
+        };
+        db.list_api_keys(&filter).expect("Failed to list keys").api_keys.into_iter().map(|key| key.id).collect()
+    };
+    assert_eq!(search(Some("INGEST"), None), vec![ingest]);
+    assert_eq!(search(Some("export"), None), vec![billing]);
+    assert_eq!(search(Some("100%"), None), vec![ingest]);
+    assert_eq!(search(Some("%"), None), vec![ingest]);
+    assert_eq!(search(None, Some(("team", "billing"))), vec![billing]);
+    assert_eq!(search(Some("staging"), Some(("team", "data"))), vec![ingest]);
+    assert!(search(None, Some(("team", "nobody"))).is_empty());
+    // JSONパスに埋め込めないラベルキーでは検索できない
+    let filter = ApiKeyFilter {
+        label: Some(("te\"am".to_string(), "billing".to_string())),
+        ..ApiKeyFilter::default()
+    };
+    assert!(matches!(db.list_api_keys(&filter), Err(ApiError::InvalidRequest(_))));
+
+    // 省略したフィールドは維持し、空文字はクリアする
+    db.update_api_key_details(ingest, &ApiKeyDetails { description: Some(String::new()), ..ApiKeyDetails::default() })
+        .expect("Failed to update key");
+    let key = db.get_api_key(ingest).unwrap();
+    assert_eq!(key.name.as_deref(), Some("Staging ingest"));
+    assert!(key.description.is_none());
+    assert_eq!(key.labels.len(), 2);
+
+    // ローテーション後も名前とラベルを引き継ぐ
+    let successor = db.rotate_api_key(ingest, "test_details_successor", "sha256", 2, None, Utc::now() + Duration::days(1))
+        .expect("Failed to rotate key");
+    let successor_key = db.get_api_key(successor).unwrap();
+    assert_eq!(successor_key.name.as_deref(), Some("Staging ingest"));
+    assert_eq!(successor_key.labels, key.labels);
+
+    // 利用ログにキー名が表示される
+    db.log_usage(successor, None, "/protected", Some("10.0.0.1"), None, true).expect("Failed to log usage");
+    db.log_usage(billing, None, "/protected", None, None, false).expect("Failed to log usage");
+    let logs = db.list_usage_logs(1, None, 10).expect("Failed to list usage logs");
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[0].api_key_name.as_deref(), Some("Billing export"));
+    let logs = db.list_usage_logs(1, Some(successor), 10).expect("Failed to list usage logs");
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].api_key_name.as_deref(), Some("Staging ingest"));
+    assert!(db.list_usage_logs(2, None, 10).unwrap().is_empty());
+
+    // 不正なラベルキーや長すぎる名前は拒否する
+    assert!(details("ok", "", &[("team", "data")]).validate().is_ok());
+    assert!(details("ok", "", &[("bad key", "x")]).validate().is_err());
+    assert!(details("ok", "", &[("k\"ey", "x")]).validate().is_err());
+    assert!(details(&"n".repeat(101), "", &[]).validate().is_err());
+
+    println!("✅ API key details test passed");
+}